@@ -0,0 +1,139 @@
+use std::time::Duration;
+
+use webrender::api::ImageKey;
+
+/// How many times an animation plays before stopping on its last frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoopCount {
+  Infinite,
+  /// Plays the given number of times, at least once.
+  Finite(u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AnimationFrame {
+  pub key: ImageKey,
+  pub delay: Duration,
+}
+
+/// The uploaded frames of an animated image, shown one after the other for their delay.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnimationInfo {
+  pub frames: Vec<AnimationFrame>,
+  pub loop_count: LoopCount,
+}
+
+impl AnimationInfo {
+  /// Time it takes to play every frame once.
+  pub fn duration(&self) -> Duration {
+    self.frames.iter().fold(Duration::new(0, 0), |total, frame| total + frame.delay)
+  }
+}
+
+/// Playhead of an animation, tracking elapsed time only. It holds no frames, so one
+/// `AnimationInfo` can drive any number of players.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct AnimationPlayer {
+  elapsed: Duration,
+}
+
+impl AnimationPlayer {
+  pub fn new() -> Self {
+    AnimationPlayer::default()
+  }
+
+  pub fn elapsed(&self) -> Duration {
+    self.elapsed
+  }
+
+  pub fn rewind(&mut self) {
+    self.elapsed = Duration::new(0, 0);
+  }
+
+  /// Moves the playhead forward by `delta` and returns the key of the frame to show, `None`
+  /// for an animation without frames.
+  pub fn advance(&mut self, animation: &AnimationInfo, delta: Duration) -> Option<ImageKey> {
+    self.elapsed += delta;
+    self.current_frame(animation)
+  }
+
+  /// Key of the frame to show at the current playhead.
+  pub fn current_frame(&self, animation: &AnimationInfo) -> Option<ImageKey> {
+    let last = animation.frames.last()?;
+    let duration = nanos(animation.duration());
+    if duration == 0 || self.is_finished(animation) {
+      return Some(last.key);
+    }
+
+    let mut position = nanos(self.elapsed) % duration;
+    for frame in &animation.frames {
+      let delay = nanos(frame.delay);
+      if position < delay {
+        return Some(frame.key);
+      }
+      position -= delay;
+    }
+    Some(last.key)
+  }
+
+  /// Whether a finite animation has played all of its loops and rests on its last frame.
+  pub fn is_finished(&self, animation: &AnimationInfo) -> bool {
+    match animation.loop_count {
+      LoopCount::Infinite => false,
+      LoopCount::Finite(loops) => {
+        nanos(self.elapsed) >= nanos(animation.duration()).saturating_mul(u64::from(loops.max(1)))
+      }
+    }
+  }
+}
+
+fn nanos(duration: Duration) -> u64 {
+  duration
+    .as_secs()
+    .saturating_mul(1_000_000_000)
+    .saturating_add(u64::from(duration.subsec_nanos()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use webrender::api::IdNamespace;
+
+  fn animation(loop_count: LoopCount) -> AnimationInfo {
+    let frame = |key, millis| AnimationFrame {
+      key: ImageKey::new(IdNamespace(0), key),
+      delay: Duration::from_millis(millis),
+    };
+    AnimationInfo {
+      frames: vec![frame(1, 100), frame(2, 50)],
+      loop_count,
+    }
+  }
+
+  #[test]
+  fn infinite_animations_wrap_around_and_finite_ones_stop_on_the_last_frame() {
+    let infinite = animation(LoopCount::Infinite);
+    let (first, second) = (infinite.frames[0].key, infinite.frames[1].key);
+    let mut player = AnimationPlayer::new();
+    assert_eq!(player.current_frame(&infinite), Some(first));
+    assert_eq!(player.advance(&infinite, Duration::from_millis(120)), Some(second));
+    // 160ms is 10ms into the second loop
+    assert_eq!(player.advance(&infinite, Duration::from_millis(40)), Some(first));
+    // 3260ms is 110ms into the 22nd loop
+    assert_eq!(player.advance(&infinite, Duration::from_millis(3100)), Some(second));
+    assert!(!player.is_finished(&infinite));
+
+    let twice = animation(LoopCount::Finite(2));
+    player.rewind();
+    assert_eq!(player.advance(&twice, Duration::from_millis(160)), Some(first));
+    assert_eq!(player.advance(&twice, Duration::from_millis(140)), Some(second));
+    assert!(player.is_finished(&twice));
+    assert_eq!(player.advance(&twice, Duration::from_millis(1000)), Some(second));
+
+    let empty = AnimationInfo {
+      frames: vec![],
+      loop_count: LoopCount::Infinite,
+    };
+    assert_eq!(player.advance(&empty, Duration::from_millis(10)), None);
+  }
+}
@@ -0,0 +1,81 @@
+use image::RgbaImage;
+
+/// Packs rectangles into a square page row by row: each shelf is as tall as the first
+/// rectangle placed on it, so inputs sorted by decreasing height pack tightest.
+#[derive(Debug, Clone)]
+pub struct ShelfPacker {
+  size: u32,
+  shelves: Vec<Shelf>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Shelf {
+  y: u32,
+  height: u32,
+  used_width: u32,
+}
+
+impl ShelfPacker {
+  pub fn new(size: u32) -> Self {
+    ShelfPacker {
+      size,
+      shelves: vec![],
+    }
+  }
+
+  /// Returns the top left corner of the allocated rectangle, or `None` when it doesn't fit.
+  pub fn allocate(&mut self, width: u32, height: u32) -> Option<(u32, u32)> {
+    if width > self.size || height > self.size {
+      return None;
+    }
+
+    for shelf in &mut self.shelves {
+      if height <= shelf.height && shelf.used_width + width <= self.size {
+        let x = shelf.used_width;
+        shelf.used_width += width;
+        return Some((x, shelf.y));
+      }
+    }
+
+    let y = self.shelves.last().map_or(0, |shelf| shelf.y + shelf.height);
+    if y + height > self.size {
+      return None;
+    }
+
+    self.shelves.push(Shelf {
+      y,
+      height,
+      used_width: width,
+    });
+    Some((0, y))
+  }
+}
+
+/// Pads the sprite with `gutter` pixels on every side repeating its edge pixels, so filtering
+/// at a sprite border never samples its neighbours on the page.
+pub fn extrude(sprite: &RgbaImage, gutter: u32) -> RgbaImage {
+  let (width, height) = sprite.dimensions();
+  if width == 0 || height == 0 {
+    return RgbaImage::new(width + 2 * gutter, height + 2 * gutter);
+  }
+
+  RgbaImage::from_fn(width + 2 * gutter, height + 2 * gutter, |x, y| {
+    let x = x.saturating_sub(gutter).min(width - 1);
+    let y = y.saturating_sub(gutter).min(height - 1);
+    *sprite.get_pixel(x, y)
+  })
+}
+
+/// Copies `sprite` into `page` with its top left corner at `x`, `y`.
+pub fn blit(page: &mut RgbaImage, sprite: &RgbaImage, x: u32, y: u32) {
+  let page_stride = page.width() as usize * 4;
+  let row_len = sprite.width() as usize * 4;
+  if row_len == 0 {
+    return;
+  }
+
+  for (row, line) in sprite.chunks(row_len).enumerate() {
+    let start = (y as usize + row) * page_stride + x as usize * 4;
+    page[start..start + row_len].copy_from_slice(line);
+  }
+}
@@ -0,0 +1,104 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use webrender::api::{
+  AsyncBlobImageRasterizer,
+  BlobImageData,
+  BlobImageError,
+  BlobImageHandler,
+  BlobImageParams,
+  BlobImageRequest,
+  BlobImageResources,
+  BlobImageResult,
+  DirtyRect,
+  FontInstanceKey,
+  FontKey,
+  IdNamespace,
+  ImageKey,
+  TileSize,
+};
+
+/// Rasterizes the commands of a blob image, called by WebRender for each tile it needs at the
+/// resolution it is displayed at. Runs on WebRender's scene builder threads.
+pub trait BlobRasterizer: Send + Sync {
+  fn rasterize(&self, commands: &[u8], params: &BlobImageParams) -> BlobImageResult;
+}
+
+/// Rasterizers by key, shared between the loader registering them and the handlers looking
+/// them up.
+pub type BlobRasterizers = Arc<Mutex<HashMap<ImageKey, Arc<dyn BlobRasterizer>>>>;
+
+/// The `BlobImageHandler` to give WebRender in `RendererOptions::blob_image_handler`. It keeps
+/// the commands WebRender sends for every blob image and dispatches tile requests to the
+/// rasterizer registered for the image with `ImageLoader::add_blob_image`.
+pub struct BlobHandler {
+  rasterizers: BlobRasterizers,
+  commands: HashMap<ImageKey, Arc<BlobImageData>>,
+}
+
+impl BlobHandler {
+  /// Handlers are usually created by `ImageLoader::blob_handler`, sharing its rasterizers.
+  pub fn new(rasterizers: BlobRasterizers) -> Self {
+    BlobHandler {
+      rasterizers,
+      commands: HashMap::new(),
+    }
+  }
+}
+
+impl BlobImageHandler for BlobHandler {
+  fn create_blob_rasterizer(&mut self) -> Box<dyn AsyncBlobImageRasterizer> {
+    let rasterizers = self.rasterizers.lock().unwrap();
+    let mut blobs = HashMap::new();
+    for (key, commands) in &self.commands {
+      if let Some(rasterizer) = rasterizers.get(key) {
+        blobs.insert(*key, (rasterizer.clone(), commands.clone()));
+      }
+    }
+    Box::new(BlobSnapshot { blobs })
+  }
+
+  fn prepare_resources(&mut self, _services: &dyn BlobImageResources, _requests: &[BlobImageParams]) {}
+
+  fn add(&mut self, key: ImageKey, data: Arc<BlobImageData>, _tiling: Option<TileSize>) {
+    self.commands.insert(key, data);
+  }
+
+  fn update(&mut self, key: ImageKey, data: Arc<BlobImageData>, _dirty_rect: &DirtyRect) {
+    self.commands.insert(key, data);
+  }
+
+  // Rasterizers are dropped by the loader when it deletes the key, WebRender processes
+  // deletes late and the key may already be registered again
+  fn delete(&mut self, key: ImageKey) {
+    self.commands.remove(&key);
+  }
+
+  fn delete_font(&mut self, _key: FontKey) {}
+
+  fn delete_font_instance(&mut self, _key: FontInstanceKey) {}
+
+  fn clear_namespace(&mut self, namespace: IdNamespace) {
+    self.commands.retain(|key, _| key.0 != namespace);
+  }
+}
+
+// The blob images as of one scene build, rasterized off the handler while it keeps receiving
+// updates
+struct BlobSnapshot {
+  blobs: HashMap<ImageKey, (Arc<dyn BlobRasterizer>, Arc<BlobImageData>)>,
+}
+
+impl AsyncBlobImageRasterizer for BlobSnapshot {
+  fn rasterize(
+    &mut self,
+    requests: &[BlobImageParams],
+    _low_priority: bool,
+  ) -> Vec<(BlobImageRequest, BlobImageResult)> {
+    let rasterize = |params: &BlobImageParams| match self.blobs.get(&params.request.key) {
+      Some(blob) => blob.0.rasterize(&blob.1, params),
+      None => Err(BlobImageError::InvalidKey),
+    };
+    requests.iter().map(|params| (params.request, rasterize(params))).collect()
+  }
+}
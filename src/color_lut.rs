@@ -0,0 +1,71 @@
+use failure::Error;
+use image::RgbaImage;
+
+/// A 3D color lookup table read from a Hald CLUT image. A level `L` Hald image is a square of
+/// `L^3` pixels per side holding an `L^2` sized color cube, with red varying fastest.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ColorLut {
+  size: usize,
+  table: Vec<[u8; 3]>,
+}
+
+#[derive(Debug, Fail)]
+#[fail(display = "{}x{} is not a valid Hald CLUT size", width, height)]
+struct InvalidLutError {
+  width: u32,
+  height: u32,
+}
+
+impl ColorLut {
+  pub fn from_hald(image: &RgbaImage) -> Result<Self, Error> {
+    let (width, height) = image.dimensions();
+    let level = (2..17).find(|level| level * level * level == width);
+    let level = match level {
+      Some(level) if width == height => level as usize,
+      _ => return Err(InvalidLutError { width, height }.into()),
+    };
+
+    let table = image.pixels().map(|pixel| [pixel.data[0], pixel.data[1], pixel.data[2]]).collect();
+    Ok(ColorLut {
+      size: level * level,
+      table,
+    })
+  }
+
+  /// Remaps the color of every pixel, alpha is left untouched.
+  pub fn apply(&self, image: &mut RgbaImage) {
+    for pixel in image.pixels_mut() {
+      let color = self.sample(pixel.data[0], pixel.data[1], pixel.data[2]);
+      pixel.data[..3].copy_from_slice(&color);
+    }
+  }
+
+  // Trilinear interpolation between the eight cube entries surrounding the color
+  fn sample(&self, r: u8, g: u8, b: u8) -> [u8; 3] {
+    let scale = (self.size - 1) as f32 / 255.0;
+    let axis = |value: u8| {
+      let position = f32::from(value) * scale;
+      let low = position.floor() as usize;
+      (low, (low + 1).min(self.size - 1), position - low as f32)
+    };
+    let (r0, r1, rt) = axis(r);
+    let (g0, g1, gt) = axis(g);
+    let (b0, b1, bt) = axis(b);
+
+    let entry = |r: usize, g: usize, b: usize| self.table[r + g * self.size + b * self.size * self.size];
+    let mut color = [0; 3];
+    for (channel, value) in color.iter_mut().enumerate() {
+      let lerp = |a: [u8; 3], b: [u8; 3], t: f32| {
+        f32::from(a[channel]) * (1.0 - t) + f32::from(b[channel]) * t
+      };
+      let c00 = lerp(entry(r0, g0, b0), entry(r1, g0, b0), rt);
+      let c10 = lerp(entry(r0, g1, b0), entry(r1, g1, b0), rt);
+      let c01 = lerp(entry(r0, g0, b1), entry(r1, g0, b1), rt);
+      let c11 = lerp(entry(r0, g1, b1), entry(r1, g1, b1), rt);
+      let c0 = c00 * (1.0 - gt) + c10 * gt;
+      let c1 = c01 * (1.0 - gt) + c11 * gt;
+      *value = (c0 * (1.0 - bt) + c1 * bt).round() as u8;
+    }
+    color
+  }
+}
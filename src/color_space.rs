@@ -0,0 +1,80 @@
+use image::RgbaImage;
+
+/// The color space image pixels were authored in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum ColorSpace {
+  #[default]
+  Srgb,
+  DisplayP3,
+}
+
+// Linear Display P3 to linear sRGB, both D65
+const P3_TO_SRGB: [[f32; 3]; 3] = [
+  [1.224_940, -0.224_940, 0.0],
+  [-0.042_057, 1.042_057, 0.0],
+  [-0.019_638, -0.078_636, 1.098_274],
+];
+
+/// Converts Display P3 pixels to sRGB, clipping colors outside the sRGB gamut. Both spaces
+/// share the sRGB transfer curve, only the primaries differ.
+pub fn display_p3_to_srgb(image: &mut RgbaImage) {
+  let mut to_linear = [0f32; 256];
+  for (value, linear) in to_linear.iter_mut().enumerate() {
+    *linear = srgb_to_linear(value as f32 / 255.0);
+  }
+
+  for pixel in image.pixels_mut() {
+    let linear = [
+      to_linear[pixel.data[0] as usize],
+      to_linear[pixel.data[1] as usize],
+      to_linear[pixel.data[2] as usize],
+    ];
+
+    for (channel, row) in P3_TO_SRGB.iter().enumerate() {
+      let value = row[0] * linear[0] + row[1] * linear[1] + row[2] * linear[2];
+      pixel.data[channel] = (linear_to_srgb(value.max(0.0).min(1.0)) * 255.0).round() as u8;
+    }
+  }
+}
+
+/// Average relative luminance of sRGB pixels, from 0 for black to 1 for white. Pixels are
+/// weighted by their alpha, a fully transparent image averages to 0.
+pub fn average_luminance(image: &RgbaImage) -> f32 {
+  let mut to_linear = [0f32; 256];
+  for (value, linear) in to_linear.iter_mut().enumerate() {
+    *linear = srgb_to_linear(value as f32 / 255.0);
+  }
+
+  let mut weighted = 0f64;
+  let mut total_alpha = 0f64;
+  for pixel in image.pixels() {
+    let [r, g, b, a] = pixel.data;
+    let luminance =
+      0.2126 * to_linear[r as usize] + 0.7152 * to_linear[g as usize] + 0.0722 * to_linear[b as usize];
+    let alpha = f64::from(a) / 255.0;
+    weighted += f64::from(luminance) * alpha;
+    total_alpha += alpha;
+  }
+
+  if total_alpha > 0.0 {
+    (weighted / total_alpha).min(1.0) as f32
+  } else {
+    0.0
+  }
+}
+
+fn srgb_to_linear(value: f32) -> f32 {
+  if value <= 0.040_45 {
+    value / 12.92
+  } else {
+    ((value + 0.055) / 1.055).powf(2.4)
+  }
+}
+
+fn linear_to_srgb(value: f32) -> f32 {
+  if value <= 0.003_130_8 {
+    value * 12.92
+  } else {
+    1.055 * value.powf(1.0 / 2.4) - 0.055
+  }
+}
@@ -1,19 +1,45 @@
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::fmt::Write;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Cursor, Read, Seek, SeekFrom};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use failure::Error;
 
-use image::{self, DynamicImage, GenericImageView, ImageError};
+#[cfg(feature = "bytes")]
+use bytes::Bytes;
+
+use image::{self, DynamicImage, FilterType, GenericImageView, ImageDecoder, ImageError, RgbaImage};
+use image::{ColorType, ImageBuffer, Pixel};
+
+use atlas::{self, ShelfPacker};
+use blob::{BlobHandler, BlobRasterizer, BlobRasterizers};
+use color_lut::ColorLut;
+use color_space::{self, ColorSpace};
+use manifest::{self, ManifestEntry};
+use pipeline::Pipeline;
+use pixel_layout::PixelLayout;
+use quantize::{self, IndexedImage};
 
 use webrender::api::{
   ExternalImageData,
   ExternalImageId,
   ImageDescriptor,
+  DeviceIntPoint,
+  ImageRendering,
   ResourceUpdate,
+  DeviceIntRect,
+  DeviceIntSize,
+  IdNamespace,
   ImageFormat,
   UpdateImage,
+  AlphaType,
   DirtyRect,
   ImageData,
   RenderApi,
+  TileSize,
   AddImage,
   ImageKey,
 };
@@ -22,7 +48,21 @@ use webrender::api::{
 pub enum ImageSource {
   AbsolutePath(PathBuf),
   AssetPath(PathBuf),
+  AssetWithRoot(String, PathBuf),
   Bundled(String),
+  /// Encoded image kept in a shared buffer, decoded in place without copying it out.
+  #[cfg(feature = "bytes")]
+  Bytes(String, Bytes),
+}
+
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
+pub enum SourceKind {
+  AbsolutePath,
+  AssetPath,
+  AssetWithRoot,
+  Bundled,
+  #[cfg(feature = "bytes")]
+  Bytes,
 }
 
 impl ImageSource {
@@ -32,15 +72,209 @@ impl ImageSource {
   pub fn asset<P: Into<PathBuf>>(path: P) -> Self {
     ImageSource::AssetPath(path.into())
   }
+  pub fn asset_with_root<R: Into<String>, P: Into<PathBuf>>(root: R, path: P) -> Self {
+    ImageSource::AssetWithRoot(root.into(), path.into())
+  }
   pub fn bundled<P: Into<String>>(name: P) -> Self {
     ImageSource::Bundled(name.into())
   }
+  #[cfg(feature = "bytes")]
+  pub fn bytes<N: Into<String>>(name: N, bytes: Bytes) -> Self {
+    ImageSource::Bytes(name.into(), bytes)
+  }
+
+  pub fn kind(&self) -> SourceKind {
+    match *self {
+      ImageSource::AbsolutePath(_) => SourceKind::AbsolutePath,
+      ImageSource::AssetPath(_) => SourceKind::AssetPath,
+      ImageSource::AssetWithRoot(..) => SourceKind::AssetWithRoot,
+      ImageSource::Bundled(_) => SourceKind::Bundled,
+      #[cfg(feature = "bytes")]
+      ImageSource::Bytes(..) => SourceKind::Bytes,
+    }
+  }
+
+  /// The path carried by the source, relative to its root for asset sources.
+  pub fn as_path(&self) -> Option<&Path> {
+    match *self {
+      ImageSource::AbsolutePath(ref path) | ImageSource::AssetPath(ref path) => Some(path),
+      ImageSource::AssetWithRoot(_, ref path) => Some(path),
+      ImageSource::Bundled(_) => None,
+      #[cfg(feature = "bytes")]
+      ImageSource::Bytes(..) => None,
+    }
+  }
+  pub fn asset_root(&self) -> Option<&str> {
+    match *self {
+      ImageSource::AssetWithRoot(ref root, _) => Some(root),
+      _ => None,
+    }
+  }
+  pub fn as_bundled_name(&self) -> Option<&str> {
+    match *self {
+      ImageSource::Bundled(ref name) => Some(name),
+      _ => None,
+    }
+  }
+
+  // The encoded bytes carried by the source itself, for in-memory sources
+  fn as_encoded(&self) -> Option<&[u8]> {
+    #[cfg(feature = "bytes")]
+    {
+      if let ImageSource::Bytes(_, ref bytes) = *self {
+        return Some(bytes);
+      }
+    }
+    None
+  }
+}
+
+/// Identifies one uploaded image: the source it was decoded from and the variant of the
+/// source it holds.
+#[derive(PartialEq, Eq, Hash, Debug, Clone)]
+pub struct CacheKey {
+  pub source: ImageSource,
+  pub variant: Variant,
+}
+
+/// The transformation applied to a source before upload, with all of its parameters.
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
+pub enum Variant {
+  Original,
+  Graded(LutId),
+  Mip(u32),
+  IntegerScaled(u32),
+  /// Processed by a `Pipeline` with the given identity.
+  Pipeline(u64),
+}
+
+impl CacheKey {
+  pub fn new(source: ImageSource, variant: Variant) -> Self {
+    CacheKey { source, variant }
+  }
+  pub fn original(source: &ImageSource) -> Self {
+    CacheKey::new(source.clone(), Variant::Original)
+  }
 }
 
 #[derive(Debug, Clone)]
 pub struct ImageInfo {
   pub key: ImageKey,
   pub descriptor: ImageDescriptor,
+  /// Set when lenient decoding could only recover the top rows of a truncated file, the
+  /// missing rows are transparent.
+  pub partial: bool,
+  pub sampling: SamplingHints,
+  /// The color space the source was tagged with. WebRender composites in sRGB, so wide gamut
+  /// sources are gamut mapped to sRGB before upload and this only records the original intent.
+  pub color_space: ColorSpace,
+}
+
+/// How display items showing the image should sample it, for the display list builder to
+/// pass along to WebRender.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SamplingHints {
+  pub rendering: ImageRendering,
+  pub alpha_type: AlphaType,
+}
+
+// Two infos refer to the same image slot when both the key and the descriptor match,
+// e.g. a reload that kept the key but changed the size compares unequal.
+impl PartialEq for ImageInfo {
+  fn eq(&self, other: &ImageInfo) -> bool {
+    self.key == other.key && self.descriptor == other.descriptor
+  }
+}
+
+/// Supplies the encoded bytes of a source, or `None` to leave it to the built-in resolution.
+pub type SourceResolver = dyn Fn(&ImageSource) -> Result<Option<Vec<u8>>, Error> + Send;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LutId(u32);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlphaIntent {
+  Preserve,
+  Opaque,
+}
+
+/// What to do with images larger than the maximum texture size in either dimension.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OversizePolicy {
+  #[default]
+  Error,
+  Downscale,
+  Tile(TileSize),
+}
+
+/// An image uploaded as horizontal bands of `band_height` rows stacked from the top, the last
+/// band may be shorter.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BandedImage {
+  pub width: u32,
+  pub height: u32,
+  pub band_height: u32,
+  pub bands: Vec<ImageInfo>,
+}
+
+/// Where each sprite of an atlas ended up: the key of its page and its rect within the page.
+pub type AtlasEntries = HashMap<String, (ImageKey, DeviceIntRect)>;
+
+/// A packed directory: where each sprite ended up and the files skipped for not being images.
+#[derive(Debug, Clone, Default)]
+pub struct AtlasReport {
+  pub entries: AtlasEntries,
+  pub skipped: Vec<PathBuf>,
+}
+
+struct Atlas {
+  pages: Vec<ImageInfo>,
+  report: AtlasReport,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ImageStats {
+  pub images: usize,
+  pub resident_bytes: u64,
+  pub cache_hits: u64,
+  pub cache_misses: u64,
+  /// Uploaded images deleted by unloading, clearing or replacing them.
+  pub evictions: u64,
+}
+
+/// One upload looked at by `ImageLoader::revalidate_all`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Upload {
+  Cached(CacheKey),
+  Trimmed(ImageSource),
+  Banded(ImageSource),
+  Atlas(PathBuf),
+}
+
+/// A source whose decoded size differs from the size registered for it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DimensionMismatch {
+  pub source: ImageSource,
+  pub registered: (u32, u32),
+  pub decoded: (u32, u32),
+}
+
+#[derive(Debug, Default)]
+pub struct RevalidationReport {
+  pub reuploaded: Vec<Upload>,
+  pub descriptor_changed: Vec<Upload>,
+  pub skipped: Vec<Upload>,
+  pub failed: Vec<(Upload, Error)>,
+}
+
+/// What `ImageLoader::warm_from_manifest` loaded and what it had to skip.
+#[derive(Debug, Default)]
+pub struct ManifestWarmReport {
+  pub warmed: usize,
+  /// Lines that don't parse as an entry.
+  pub malformed: Vec<String>,
+  /// Entries whose sources no longer load, with the error.
+  pub failed: Vec<(ManifestEntry, Error)>,
 }
 
 #[derive(Debug, Fail)]
@@ -49,13 +283,129 @@ struct BundledImageMissingError {
   name: String,
 }
 
+#[cfg(feature = "bytes")]
+#[derive(Debug, Fail)]
+#[fail(display = "image {} is held in memory and has no file path", name)]
+struct NoSourcePathError {
+  name: String,
+}
+
+#[derive(Debug, Fail)]
+#[fail(display = "image of {}x{} exceeds the maximum texture size {}", width, height, max_size)]
+struct TextureTooLargeError {
+  width: u32,
+  height: u32,
+  max_size: u32,
+}
+
+#[derive(Debug, Fail)]
+#[fail(display = "loading took longer than the {:?} deadline", deadline)]
+struct LoadTimeoutError {
+  deadline: Duration,
+}
+
+#[derive(Debug, Fail)]
+#[fail(display = "unknown color lut {:?}", lut)]
+struct LutMissingError {
+  lut: LutId,
+}
+
+#[derive(Debug, Fail)]
+#[fail(display = "{:?} images can't be produced again from their source", variant)]
+struct VariantNotReproducibleError {
+  variant: Variant,
+}
+
+#[derive(Debug, Fail)]
+#[fail(display = "unknown asset root {}", name)]
+struct AssetRootMissingError {
+  name: String,
+}
+
+/// Identifies a window, or any other document with its own render API, the loader uploads to.
+/// The render API set by `init_resources` belongs to the default window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct WindowId(pub u32);
+
+/// The render API calls the loader uploads with, implemented by WebRender's `RenderApi`. Only
+/// `Send` is required, like `RenderApi` an implementation is moved to the loader's thread and
+/// never shared.
+pub trait ResourceApi: Send {
+  fn generate_image_key(&self) -> ImageKey;
+  fn update_resources(&self, resources: Vec<ResourceUpdate>);
+  fn get_namespace_id(&self) -> IdNamespace;
+}
+
+impl ResourceApi for RenderApi {
+  fn generate_image_key(&self) -> ImageKey {
+    RenderApi::generate_image_key(self)
+  }
+  fn update_resources(&self, resources: Vec<ResourceUpdate>) {
+    RenderApi::update_resources(self, resources)
+  }
+  fn get_namespace_id(&self) -> IdNamespace {
+    RenderApi::get_namespace_id(self)
+  }
+}
+
+/// Everything uploaded through the render API of one window, with its keys and counters. The
+/// loader holds the active window's, the others are parked until their window is activated.
+#[derive(Default)]
+pub struct WindowUploads {
+  pub render: Option<Box<dyn ResourceApi>>,
+  pub images: HashMap<CacheKey, ImageInfo>,
+  pub texture_descriptors: HashMap<u64, ImageDescriptor>,
+  banded_images: HashMap<ImageSource, BandedImage>,
+  atlases: HashMap<PathBuf, Atlas>,
+  trimmed_images: HashMap<ImageSource, (ImageInfo, DeviceIntRect)>,
+  checkerboards: HashMap<u32, ImageInfo>,
+  rounded_masks: HashMap<(u32, u32, u32), ImageInfo>,
+  blob_images: HashMap<String, ImageInfo>,
+  free_keys: Vec<ImageKey>,
+  generations: HashMap<ImageSource, u64>,
+  lookups: HashMap<SourceKind, LookupCounters>,
+  evictions: u64,
+}
+
+/// Uploads images to WebRender and caches them per source. The loader is `Send` but not
+/// `Sync` as it owns a render API, any hooks installed on it must be `Send` as well.
 #[derive(Default)]
 pub struct ImageLoader {
-  pub render: Option<RenderApi>,
+  /// The uploads of the active window.
+  pub uploads: WindowUploads,
   pub assets_path: PathBuf,
+  pub asset_roots: HashMap<String, PathBuf>,
   pub bundled_images: HashMap<ImageSource, ImageInfo>,
-  pub images: HashMap<ImageSource, ImageInfo>,
-  pub texture_descriptors: HashMap<u64, ImageDescriptor>,
+  staged_images: HashMap<ImageSource, (ImageData, ImageDescriptor, bool)>,
+  color_luts: HashMap<LutId, ColorLut>,
+  lut_sources: HashMap<ImageSource, LutId>,
+  alpha_histograms: HashMap<ImageSource, [u32; 256]>,
+  perceptual_hashes: HashMap<ImageSource, u64>,
+  average_luminances: HashMap<ImageSource, f32>,
+  source_resolver: Option<Box<SourceResolver>>,
+  blob_rasterizers: BlobRasterizers,
+  straight_alpha: bool,
+  device_pixel_ratio: Option<f32>,
+  load_deadline: Option<Duration>,
+  lenient_decoding: bool,
+  dither_quantized: bool,
+  alpha_intents: HashMap<ImageSource, AlphaIntent>,
+  registered_dimensions: HashMap<ImageSource, (u32, u32)>,
+  dimension_mismatches: Vec<DimensionMismatch>,
+  color_spaces: HashMap<ImageSource, ColorSpace>,
+  sampling_hints: HashMap<ImageSource, SamplingHints>,
+  max_texture_size: Option<u32>,
+  oversize_policy: OversizePolicy,
+  key_pool_capacity: usize,
+  generation: u64,
+  active_window: WindowId,
+  parked_windows: HashMap<WindowId, WindowUploads>,
+}
+
+#[derive(Default, Clone, Copy)]
+struct LookupCounters {
+  hits: u64,
+  misses: u64,
 }
 
 impl ImageLoader {
@@ -63,6 +413,100 @@ impl ImageLoader {
     ImageLoader::default()
   }
 
+  /// Controls whether decoded BGRA8 pixels are premultiplied before upload (the default).
+  /// Disable it when the target pipeline expects straight alpha and blends on its own.
+  pub fn set_premultiply(&mut self, premultiply: bool) {
+    self.straight_alpha = !premultiply;
+  }
+
+  /// Overrides how the alpha channel of the source is treated on its next load. With
+  /// `AlphaIntent::Opaque` every alpha byte is rewritten to 255 and the descriptor is marked
+  /// opaque, enabling WebRender's opaque path for content known to be visually opaque even
+  /// when its alpha channel carries noise. Applies to every variant, band and analysis of the
+  /// source.
+  pub fn treat_alpha_as(&mut self, source: &ImageSource, intent: AlphaIntent) {
+    match intent {
+      AlphaIntent::Preserve => self.alpha_intents.remove(source),
+      AlphaIntent::Opaque => self.alpha_intents.insert(source.clone(), intent),
+    };
+  }
+
+  /// Tags the source as authored in the given color space, applied on its next load.
+  pub fn set_color_space(&mut self, source: &ImageSource, color_space: ColorSpace) {
+    match color_space {
+      ColorSpace::Srgb => self.color_spaces.remove(source),
+      _ => self.color_spaces.insert(source.clone(), color_space),
+    };
+  }
+
+  /// Sets the largest texture dimension the GPU accepts, images over it are handled according
+  /// to the oversize policy. There is no limit by default.
+  pub fn set_max_texture_size(&mut self, max_size: u32) {
+    self.max_texture_size = Some(max_size);
+  }
+
+  pub fn set_oversize_policy(&mut self, policy: OversizePolicy) {
+    self.oversize_policy = policy;
+  }
+
+  /// Sets the ratio of device pixels to layout pixels used by `get_image_for_scale`, 1 by
+  /// default.
+  pub fn set_device_pixel_ratio(&mut self, device_pixel_ratio: f32) {
+    self.device_pixel_ratio = Some(device_pixel_ratio);
+  }
+
+  /// Makes `get_image` give up with a timeout error on images that take longer than
+  /// `deadline` to load. The elapsed time is checked after decoding and after preparing the
+  /// pixels: a synchronous decode can't be interrupted midway, so a pathological file still
+  /// blocks for as long as its decode takes, but it is never uploaded.
+  pub fn set_load_deadline(&mut self, deadline: Duration) {
+    self.load_deadline = Some(deadline);
+  }
+
+  /// Opts into uploading whatever rows of a truncated or corrupt PNG file still decode instead
+  /// of failing, e.g. to show a partially downloaded image. Such images are flagged with
+  /// `ImageInfo::partial`. Only non-interlaced PNG files can be recovered, as their rows are
+  /// stored in order, other formats and IO errors still fail the load.
+  pub fn set_lenient_decoding(&mut self, lenient: bool) {
+    self.lenient_decoding = lenient;
+  }
+
+  /// Diffuses the quantization error of `save_image_quantized` to neighbouring pixels, which
+  /// trades banding in gradients for noise. Off by default.
+  pub fn set_quantize_dithering(&mut self, dither: bool) {
+    self.dither_quantized = dither;
+  }
+
+  /// Installs a resolver consulted for every source before the built-in filesystem lookup,
+  /// e.g. to serve images from a cache, a CDN or an encrypted store. Sources it returns
+  /// `None` for fall back to the built-in resolution.
+  pub fn set_source_resolver<F>(&mut self, resolver: F)
+  where
+    F: Fn(&ImageSource) -> Result<Option<Vec<u8>>, Error> + Send + 'static,
+  {
+    self.source_resolver = Some(Box::new(resolver));
+  }
+
+  /// Registers a named bundle root that `ImageSource::AssetWithRoot` paths resolve against.
+  pub fn register_asset_root<N: Into<String>, P: Into<PathBuf>>(&mut self, name: N, path: P) {
+    self.asset_roots.insert(name.into(), path.into());
+  }
+
+  /// Opts into recycling the keys of unloaded images for later uploads instead of generating
+  /// new ones. A key only goes back to the pool after its `DeleteImage` has been queued, and
+  /// since resource updates reach WebRender in order, the deletion is always processed
+  /// before the `AddImage` reusing the key. Each window keeps at most `capacity` keys, keys
+  /// deleted while its pool is full are dropped. A capacity of 0 disables pooling.
+  pub fn set_key_pool_capacity(&mut self, capacity: usize) {
+    self.key_pool_capacity = capacity;
+    self.uploads.free_keys.truncate(capacity);
+    for uploads in self.parked_windows.values_mut() {
+      uploads.free_keys.truncate(capacity);
+    }
+  }
+
+  /// Loads the source once per window and uploads it through the render API of the active
+  /// window, see `set_active_window`.
   pub fn get_image(&mut self, source: &ImageSource) -> Result<&ImageInfo, Error> {
     let image = self.get_image_internal(source);
     if let Err(ref error) = image {
@@ -72,147 +516,3340 @@ impl ImageLoader {
   }
 
   fn get_image_internal(&mut self, source: &ImageSource) -> Result<&ImageInfo, Error> {
-    if self.images.contains_key(source) {
-      Ok(&self.images[source])
+    let cache_key = CacheKey::original(source);
+    if self.uploads.images.contains_key(&cache_key) {
+      self.uploads.lookups.entry(source.kind()).or_default().hits += 1;
+      Ok(&self.uploads.images[&cache_key])
     } else {
-      let (data, descriptor) = match *source {
-        ImageSource::AbsolutePath(ref path) => prepare_image(image::open(&path)?)?,
-        ImageSource::AssetPath(ref relative_path) => {
-          let mut path = PathBuf::from(&self.assets_path);
-          path.push(relative_path);
-          prepare_image(image::open(&path)?)?
-        }
-        ImageSource::Bundled(ref name) => {
-          return Err(
-            BundledImageMissingError {
-              name: name.to_owned(),
-            }.into(),
-          )
+      self.uploads.lookups.entry(source.kind()).or_default().misses += 1;
+      let (data, descriptor, partial) = match self.staged_images.remove(source) {
+        Some(staged) => staged,
+        None => {
+          let started = Instant::now();
+          let (image, partial) = self.decode_for_upload(source)?;
+          self.check_registered_dimensions(source, image.dimensions());
+          self.check_deadline(started)?;
+          let (data, descriptor) = self.prepare(image)?;
+          self.check_deadline(started)?;
+          (data, descriptor, partial)
         }
       };
+      Ok(self.put_image(source, data, descriptor, partial))
+    }
+  }
 
-      Ok(self.put_image(source, data, descriptor))
+  /// Same as `get_image`, recording how the image should be sampled. The hints are kept for the
+  /// source, so later `get_image` calls in any window return them too.
+  pub fn get_image_with_sampling(
+    &mut self,
+    source: &ImageSource,
+    sampling: SamplingHints,
+  ) -> Result<&ImageInfo, Error> {
+    self.sampling_hints.insert(source.clone(), sampling);
+    let cache_key = CacheKey::original(source);
+    let windows = self.parked_windows.values_mut().chain(Some(&mut self.uploads));
+    for info in windows.filter_map(|uploads| uploads.images.get_mut(&cache_key)) {
+      info.sampling = sampling;
     }
+    self.get_image(source)
   }
 
-  fn put_image(&mut self, source: &ImageSource, data: ImageData, descriptor: ImageDescriptor) -> &ImageInfo {
-    let image_info = self.create_image_resource(data, descriptor);
-    self.images.insert(source.clone(), image_info);
-    &self.images[source]
+  /// Uploads the mip level of the source closest to the size it is displayed at, where
+  /// `display_scale` is the layout size relative to the image size. Each level halves the
+  /// image, so an image drawn at a quarter of its size on a 1x display gets level 2. Level 0 is
+  /// the image `get_image` returns, other levels are cached per source.
+  pub fn get_image_for_scale(
+    &mut self,
+    source: &ImageSource,
+    display_scale: f32,
+  ) -> Result<ImageInfo, Error> {
+    let device_scale = display_scale * self.device_pixel_ratio.unwrap_or(1.0);
+    let level = if device_scale > 0.0 {
+      (-device_scale.log2()).round().max(0.0).min(31.0) as u32
+    } else {
+      0
+    };
+
+    self.get_image_mip(source, level)
   }
 
-  pub fn create_image_resource(&mut self, data: ImageData, descriptor: ImageDescriptor) -> ImageInfo {
-    let key = self.render_api().generate_image_key();
-    let resource = ResourceUpdate::AddImage(AddImage {
-      tiling: None,
-      descriptor,
-      data,
-      key,
-    });
+  fn get_image_mip(&mut self, source: &ImageSource, level: u32) -> Result<ImageInfo, Error> {
+    if level == 0 {
+      return self.get_image(source).cloned();
+    }
 
-    self.render_api().update_resources(vec![resource]);
+    let mip_key = CacheKey::new(source.clone(), Variant::Mip(level));
+    if let Some(info) = self.uploads.images.get(&mip_key) {
+      return Ok(info.clone());
+    }
 
-    ImageInfo {
-      descriptor,
-      key,
+    let (mip, partial) = self.variant_pixels(source, Variant::Mip(level))?;
+    let (data, descriptor) = self.prepare(mip)?;
+    let image_info = self.create_source_resource(source, data, descriptor, partial);
+    self.uploads.images.insert(mip_key, image_info.clone());
+    self.tag_generation(source);
+    Ok(image_info)
+  }
+
+  /// Records the size of a source known ahead of time, e.g. from a generated asset manifest,
+  /// so `probe_dimensions` answers without any IO. The size is checked against the decoded
+  /// image once the source is actually loaded, a mismatch replaces the registered size and is
+  /// reported by `take_dimension_mismatches`.
+  pub fn register_dimensions(&mut self, source: &ImageSource, width: u32, height: u32) {
+    self.registered_dimensions.insert(source.clone(), (width, height));
+  }
+
+  /// Size of the source image, from registered dimensions or by reading just the header of
+  /// PNG and JPEG files. Other formats are decoded in full.
+  pub fn probe_dimensions(&self, source: &ImageSource) -> Result<(u32, u32), Error> {
+    if let Some(&dimensions) = self.registered_dimensions.get(source) {
+      return Ok(dimensions);
+    }
+
+    match probe_header(self.source_reader(source)?)? {
+      Some(dimensions) => Ok(dimensions),
+      None => Ok(self.decode_source(source)?.dimensions()),
     }
   }
 
-  pub fn update_texture(&mut self, key: ImageKey, descriptor: ImageDescriptor, data: ExternalImageData) {
-    let resource = ResourceUpdate::UpdateImage(UpdateImage {
-      data: ImageData::External(data),
-      dirty_rect: DirtyRect::All,
-      descriptor,
-      key,
-    });
+  /// Returns the sources decoded at another size than the one registered for them since the
+  /// last call, e.g. to flag a stale asset manifest.
+  pub fn take_dimension_mismatches(&mut self) -> Vec<DimensionMismatch> {
+    self.dimension_mismatches.drain(..).collect()
+  }
 
-    self.render_api().update_resources(vec![resource]);
+  fn check_registered_dimensions(&mut self, source: &ImageSource, dimensions: (u32, u32)) {
+    if let Some(registered) = self.registered_dimensions.get_mut(source) {
+      if *registered != dimensions {
+        self.dimension_mismatches.push(DimensionMismatch {
+          source: source.clone(),
+          registered: *registered,
+          decoded: dimensions,
+        });
+        *registered = dimensions;
+      }
+    }
+  }
 
-    let ExternalImageData {
-      id: ExternalImageId(texture_id),
-      ..
-    } = data;
+  /// Uploads the source upscaled by an exact integer `factor`, every pixel replicated into a
+  /// `factor` sized block, with the pixelated sampling hint so pixel art stays crisp. Cached per
+  /// source and factor.
+  pub fn get_image_integer_scaled(&mut self, source: &ImageSource, factor: u32) -> Result<ImageInfo, Error> {
+    let scaled_key = CacheKey::new(source.clone(), Variant::IntegerScaled(factor));
+    if let Some(info) = self.uploads.images.get(&scaled_key) {
+      return Ok(info.clone());
+    }
 
-    self.texture_descriptors.insert(texture_id, descriptor);
+    let (scaled, partial) = self.variant_pixels(source, Variant::IntegerScaled(factor))?;
+    let (data, descriptor) = self.prepare(scaled)?;
+    let mut image_info = self.create_source_resource(source, data, descriptor, partial);
+    image_info.sampling.rendering = ImageRendering::Pixelated;
+    self.uploads.images.insert(scaled_key, image_info.clone());
+    self.tag_generation(source);
+    Ok(image_info)
   }
 
-  pub fn load_image(&mut self, name: &str, data: Vec<u8>) -> Result<(), Error> {
-    if let Err(error) = self.load_image_internal(name, data) {
-      bail!("Failed to load image from raw data {}", error);
+  /// Uploads the source after running its decoded pixels through the stages of `pipeline`.
+  /// Cached per source and pipeline identity, an empty pipeline is the same as `get_image`.
+  pub fn get_image_with_pipeline(
+    &mut self,
+    source: &ImageSource,
+    pipeline: &Pipeline,
+  ) -> Result<ImageInfo, Error> {
+    if pipeline.is_empty() {
+      return self.get_image(source).cloned();
     }
 
-    Ok(())
+    let pipeline_key = CacheKey::new(source.clone(), Variant::Pipeline(pipeline.identity()));
+    if let Some(info) = self.uploads.images.get(&pipeline_key) {
+      return Ok(info.clone());
+    }
+
+    let (image, partial) = self.decode_for_upload(source)?;
+    let mut rgba = image.to_rgba();
+    pipeline.process(&mut rgba);
+
+    let (data, descriptor) = self.prepare(DynamicImage::ImageRgba8(rgba))?;
+    let image_info = self.create_source_resource(source, data, descriptor, partial);
+    self.uploads.images.insert(pipeline_key, image_info.clone());
+    self.tag_generation(source);
+    Ok(image_info)
   }
 
-  fn load_image_internal(&mut self, name: &str, data: Vec<u8>) -> Result<(), Error> {
-    let (data, descriptor) = prepare_image(image::load_from_memory(&data)?)?;
-    let image_info = self.create_image_resource(data, descriptor);
-    self.images.insert(ImageSource::bundled(name), image_info);
-    Ok(())
+  /// Uploads only the bounding box of the non-transparent pixels and returns it together with
+  /// the box position in the original image, so layout can offset the trimmed image. A fully
+  /// transparent source yields a 1x1 transparent image and an empty rect.
+  pub fn get_image_trimmed(&mut self, source: &ImageSource) -> Result<(ImageInfo, DeviceIntRect), Error> {
+    if let Some(trimmed) = self.uploads.trimmed_images.get(source) {
+      return Ok(trimmed.clone());
+    }
+
+    let (image, partial) = self.decode_for_upload(source)?;
+    let (trimmed, rect) = trim_transparent(image.to_rgba());
+    let (data, descriptor) = self.prepare(trimmed)?;
+    let image_info = self.create_source_resource(source, data, descriptor, partial);
+    self.uploads.trimmed_images.insert(source.clone(), (image_info.clone(), rect));
+    self.tag_generation(source);
+    Ok((image_info, rect))
   }
 
-  fn render_api(&self) -> &RenderApi {
-    let api = self.render.as_ref();
-    println!("Get Render API: {}", api.is_some());
-    api.unwrap()
+  /// Decodes the source scanline by scanline and uploads it as separate band images as soon as
+  /// each band is complete, so peak memory is bounded by the band height instead of the image
+  /// height. Supported for non-interlaced PNG files.
+  pub fn get_image_banded(&mut self, source: &ImageSource, band_height: u32) -> Result<BandedImage, Error> {
+    self.get_image_banded_with_progress(source, band_height, |_, _| {})
   }
-}
 
-fn prepare_image(image: DynamicImage) -> Result<(ImageData, ImageDescriptor), Error> {
-  let image_dims = image.dimensions();
+  /// Same as `get_image_banded`, calling `progress` with the number of rows decoded so far and
+  /// the image height after each scanline. Not called when the banded image is cached.
+  pub fn get_image_banded_with_progress<F: FnMut(u32, u32)>(
+    &mut self,
+    source: &ImageSource,
+    band_height: u32,
+    progress: F,
+  ) -> Result<BandedImage, Error> {
+    if let Some(banded) = self.uploads.banded_images.get(source) {
+      if banded.band_height == band_height {
+        return Ok(banded.clone());
+      }
+    }
+    if let Some(banded) = self.uploads.banded_images.remove(source) {
+      self.delete_keys(banded.bands.iter().map(|info| info.key).collect());
+    }
+
+    let mut reader = self.source_reader(source)?;
+    check_scanline_png(&mut reader)?;
+    let banded = self.upload_bands(source, image::png::PNGDecoder::new(reader), band_height, progress)?;
+
+    self.uploads.banded_images.insert(source.clone(), banded.clone());
+    self.tag_generation(source);
+    Ok(banded)
+  }
+
+  fn upload_bands<D: ImageDecoder, F: FnMut(u32, u32)>(
+    &mut self,
+    source: &ImageSource,
+    mut decoder: D,
+    band_height: u32,
+    mut progress: F,
+  ) -> Result<BandedImage, Error> {
+    let band_height = band_height.max(1);
+    let (width, height) = decoder.dimensions()?;
+    let color = decoder.colortype()?;
+    let row_len = decoder.row_len()?;
 
-  let format = match image {
-    image::ImageRgba8(_) => ImageFormat::BGRA8,
-    image::ImageLuma8(_) => ImageFormat::R8,
+    let mut banded = BandedImage {
+      width,
+      height,
+      band_height,
+      bands: vec![],
+    };
 
-    _ => {
-      let message = "ImageFormat unsupported".to_string();
-      let error = ImageError::UnsupportedError(message).into();
-      return Err(error);
+    let mut row = vec![0; row_len];
+    let mut band = Vec::with_capacity(row_len * band_height as usize);
+    for y in 0..height {
+      decoder.read_scanline(&mut row)?;
+      band.extend_from_slice(&row);
+      progress(y + 1, height);
+
+      let rows = y % band_height + 1;
+      if rows == band_height || y + 1 == height {
+        let pixels = band_to_image(color, width, rows, band.split_off(0))?;
+        let (data, descriptor) = self.prepare(self.apply_source_options(source, pixels))?;
+        banded.bands.push(self.create_source_resource(source, data, descriptor, false));
+      }
     }
-  };
 
-  let mut bytes = image.raw_pixels();
-  if format == ImageFormat::BGRA8 {
-    premultiply(bytes.as_mut_slice());
+    Ok(banded)
   }
 
-  let opaque = is_image_opaque(format, &bytes[..]);
-  let descriptor = ImageDescriptor::new(image_dims.0 as i32, image_dims.1 as i32, format, opaque, false);
-  let data = ImageData::new(bytes);
+  /// Loads a Hald CLUT image for use with `get_image_graded`. Loading the same source again
+  /// returns the same id.
+  pub fn load_color_lut(&mut self, source: &ImageSource) -> Result<LutId, Error> {
+    if let Some(&lut) = self.lut_sources.get(source) {
+      return Ok(lut);
+    }
 
-  Ok((data, descriptor))
-}
+    let color_lut = ColorLut::from_hald(&self.decode_source(source)?.to_rgba())?;
+    let lut = LutId(self.color_luts.len() as u32);
+    self.color_luts.insert(lut, color_lut);
+    self.lut_sources.insert(source.clone(), lut);
+    Ok(lut)
+  }
+
+  /// Uploads the source with its colors remapped through a loaded lookup table, cached per
+  /// source and table.
+  pub fn get_image_graded(&mut self, source: &ImageSource, lut: LutId) -> Result<ImageInfo, Error> {
+    let graded_key = CacheKey::new(source.clone(), Variant::Graded(lut));
+    if let Some(info) = self.uploads.images.get(&graded_key) {
+      return Ok(info.clone());
+    }
+
+    let (graded, partial) = self.variant_pixels(source, Variant::Graded(lut))?;
+    let (data, descriptor) = self.prepare(graded)?;
+    let image_info = self.create_source_resource(source, data, descriptor, partial);
+    self.uploads.images.insert(graded_key, image_info.clone());
+    self.tag_generation(source);
+    Ok(image_info)
+  }
+
+  /// Uploads an opaque two-cell checkerboard of light gray and white `cell` pixel squares, for
+  /// tiling behind transparent images. Generated once per cell size.
+  pub fn transparency_checkerboard(&mut self, cell: u32) -> Result<ImageInfo, Error> {
+    if let Some(info) = self.uploads.checkerboards.get(&cell) {
+      return Ok(info.clone());
+    }
+    if cell == 0 {
+      bail!("Checkerboard cells must be at least one pixel");
+    }
+    let size = cell.checked_mul(2);
+    let length = size.and_then(|size| size.checked_mul(size)).and_then(|area| area.checked_mul(3));
+    let (size, length) = match (size, length) {
+      (Some(size), Some(length)) => (size, length),
+      _ => bail!("Checkerboard cells of {} pixels overflow the texture size", cell),
+    };
+
+    let mut pixels = Vec::with_capacity(length as usize);
+    for y in 0..size {
+      for x in 0..size {
+        let value = if (x / cell + y / cell) % 2 == 0 { 0xff } else { 0xcc };
+        pixels.extend_from_slice(&[value, value, value]);
+      }
+    }
+
+    let image = ImageBuffer::from_raw(size, size, pixels).unwrap();
+    let (data, descriptor) = self.prepare(DynamicImage::ImageRgb8(image))?;
+    let image_info = self.create_image_resource(data, descriptor);
+    self.uploads.checkerboards.insert(cell, image_info.clone());
+    Ok(image_info)
+  }
+
+  /// Uploads an antialiased R8 mask of a rounded rectangle filling `width` x `height`, for use
+  /// as an image clip mask. The radius is capped to half the smaller side. Generated once per
+  /// size and radius.
+  pub fn rounded_rect_mask(&mut self, width: u32, height: u32, radius: u32) -> Result<ImageInfo, Error> {
+    let mask_key = (width, height, radius);
+    if let Some(info) = self.uploads.rounded_masks.get(&mask_key) {
+      return Ok(info.clone());
+    }
+    if width == 0 || height == 0 {
+      bail!("Can't generate an empty {}x{} mask", width, height);
+    }
+    if width.checked_mul(height).is_none() {
+      bail!("A {}x{} mask overflows the texture size", width, height);
+    }
+
+    let coverage = rounded_rect_coverage(width, height, radius);
+    let image = ImageBuffer::from_raw(width, height, coverage).unwrap();
+    let (data, descriptor) = self.prepare(DynamicImage::ImageLuma8(image))?;
+    let image_info = self.create_image_resource(data, descriptor);
+    self.uploads.rounded_masks.insert(mask_key, image_info.clone());
+    Ok(image_info)
+  }
+
+  /// Decodes every image file in `dir` and packs them into square atlas pages of `max_page`
+  /// pixels, returning each file name with its page key and rect. Sprites are separated by
+  /// `gutter` pixels on each side repeating their edge pixels. One pixel is enough for linear
+  /// filtering, downscaled sampling needs more, and 0 packs sprites edge to edge for pixelated
+  /// sampling. Files that don't fit a page with their gutter are uploaded standalone, subject to
+  /// the oversize policy, their rect covering the whole uploaded image. Files in a format the
+  /// image crate doesn't know are skipped and listed in the report. Pages hold sprites already
+  /// mapped to sRGB and are tagged as such. Cached per directory, whatever the page size and
+  /// gutter of later calls.
+  pub fn build_atlas_from_dir(
+    &mut self,
+    dir: &Path,
+    max_page: u32,
+    gutter: u32,
+  ) -> Result<AtlasReport, Error> {
+    if let Some(atlas) = self.uploads.atlases.get(dir) {
+      return Ok(atlas.report.clone());
+    }
+    if let Some(max_size) = self.max_texture_size {
+      if max_page > max_size {
+        return Err(
+          TextureTooLargeError {
+            width: max_page,
+            height: max_page,
+            max_size,
+          }.into(),
+        );
+      }
+    }
+
+    let mut atlas = Atlas {
+      pages: vec![],
+      report: AtlasReport::default(),
+    };
+    let mut sprites = vec![];
+    for entry in fs::read_dir(dir)? {
+      let path = entry?.path();
+      if !path.is_file() {
+        continue;
+      }
+
+      let name = path.file_name().unwrap().to_string_lossy().into_owned();
+      let source = ImageSource::absolute(&path);
+      let sprite = match self.decode_for_upload(&source) {
+        Ok((image, _)) => image.to_rgba(),
+        Err(error) => {
+          if let Some(&ImageError::UnsupportedError(_)) = error.downcast_ref::<ImageError>() {
+            atlas.report.skipped.push(path);
+            continue;
+          }
+          bail!("Failed to load atlas image {}. {}", path.display(), error)
+        }
+      };
+      sprites.push((name, source, sprite));
+    }
+    sprites.sort_by(|a, b| b.2.height().cmp(&a.2.height()));
+
+    let mut pages: Vec<(ShelfPacker, RgbaImage, Vec<(String, DeviceIntRect)>)> = vec![];
+    for (name, source, sprite) in sprites {
+      let (width, height) = sprite.dimensions();
+      let size = DeviceIntSize::new(width as i32, height as i32);
+      let (padded_width, padded_height) = (width + 2 * gutter, height + 2 * gutter);
+      if padded_width > max_page || padded_height > max_page {
+        let (data, descriptor) = self.prepare(DynamicImage::ImageRgba8(sprite))?;
+        let rect = DeviceIntRect::new(DeviceIntPoint::zero(), descriptor.size);
+        let info = self.create_source_resource(&source, data, descriptor, false);
+        atlas.report.entries.insert(name, (info.key, rect));
+        atlas.pages.push(info);
+        continue;
+      }
 
-fn is_image_opaque(format: ImageFormat, bytes: &[u8]) -> bool {
-  match format {
-    ImageFormat::BGRA8 => {
-      let mut is_opaque = true;
-      for i in 0..(bytes.len() / 4) {
-        if bytes[i * 4 + 3] != 255 {
-          is_opaque = false;
+      let mut allocation = None;
+      for (index, page) in pages.iter_mut().enumerate() {
+        if let Some(origin) = page.0.allocate(padded_width, padded_height) {
+          allocation = Some((index, origin));
           break;
         }
       }
-      is_opaque
+      let (index, (x, y)) = match allocation {
+        Some(allocation) => allocation,
+        None => {
+          let mut packer = ShelfPacker::new(max_page);
+          let origin = packer.allocate(padded_width, padded_height).unwrap();
+          pages.push((packer, RgbaImage::new(max_page, max_page), vec![]));
+          (pages.len() - 1, origin)
+        }
+      };
+
+      let page = &mut pages[index];
+      atlas::blit(&mut page.1, &atlas::extrude(&sprite, gutter), x, y);
+      let origin = DeviceIntPoint::new((x + gutter) as i32, (y + gutter) as i32);
+      page.2.push((name, DeviceIntRect::new(origin, size)));
+    }
+
+    // Pages never exceed the maximum texture size, checked above
+    for (_, page, placed) in pages {
+      let (data, descriptor) = self.prepare(DynamicImage::ImageRgba8(page))?;
+      let info = self.create_image_resource(data, descriptor);
+      for (name, rect) in placed {
+        atlas.report.entries.insert(name, (info.key, rect));
+      }
+      atlas.pages.push(info);
     }
-    ImageFormat::R8 => true,
-    _ => unreachable!(),
+
+    let report = atlas.report.clone();
+    self.uploads.atlases.insert(dir.to_path_buf(), atlas);
+    Ok(report)
   }
-}
 
-// From webrender/wrench
-// These are slow. Gecko's gfx/2d/Swizzle.cpp has better versions
-pub fn premultiply(data: &mut [u8]) {
-  for pixel in data.chunks_mut(4) {
-    let a = u32::from(pixel[3]);
-    let r = u32::from(pixel[2]);
-    let g = u32::from(pixel[1]);
-    let b = u32::from(pixel[0]);
+  /// Returns the decoded pixels of the source as straight alpha RGBA, with its color space and
+  /// alpha intent applied as for upload, for further processing with the image crate and
+  /// re-loading through `load_dynamic_image`.
+  pub fn as_rgba_image(&self, source: &ImageSource) -> Result<RgbaImage, Error> {
+    Ok(self.decode_for_upload(source)?.0.to_rgba())
+  }
 
-    pixel[3] = a as u8;
-    pixel[2] = ((r * a + 128) / 255) as u8;
-    pixel[1] = ((g * a + 128) / 255) as u8;
-    pixel[0] = ((b * a + 128) / 255) as u8;
+  /// Counts the pixels of the source at each alpha level, so callers can pick between an
+  /// opaque, alpha-tested or fully blended path. The result is cached per source.
+  pub fn alpha_histogram(&mut self, source: &ImageSource) -> Result<[u32; 256], Error> {
+    if let Some(histogram) = self.alpha_histograms.get(source) {
+      return Ok(*histogram);
+    }
+
+    let mut histogram = [0u32; 256];
+    for pixel in self.decode_for_upload(source)?.0.to_rgba().pixels() {
+      histogram[pixel.data[3] as usize] += 1;
+    }
+
+    self.alpha_histograms.insert(source.clone(), histogram);
+    Ok(histogram)
+  }
+
+  /// Average relative luminance of the source from 0 to 1, e.g. to pick dark or light text
+  /// over it. Cached per source.
+  pub fn average_luminance(&mut self, source: &ImageSource) -> Result<f32, Error> {
+    if let Some(&luminance) = self.average_luminances.get(source) {
+      return Ok(luminance);
+    }
+
+    let luminance = color_space::average_luminance(&self.decode_for_upload(source)?.0.to_rgba());
+    self.average_luminances.insert(source.clone(), luminance);
+    Ok(luminance)
+  }
+
+  /// Computes a 64 bit difference hash (dHash) of the source: the image is reduced to 9x8
+  /// grayscale and each bit records whether a pixel is brighter than its right neighbour.
+  /// Hashes of visually similar images differ in few bits, a `hamming_distance` of 10 or less
+  /// is a good threshold for near duplicates. The result is cached per source.
+  pub fn perceptual_hash(&mut self, source: &ImageSource) -> Result<u64, Error> {
+    if let Some(&hash) = self.perceptual_hashes.get(source) {
+      return Ok(hash);
+    }
+
+    let reduced = self.decode_source(source)?.resize_exact(9, 8, FilterType::Triangle).to_luma();
+    let mut hash = 0u64;
+    for y in 0..8 {
+      for x in 0..8 {
+        hash <<= 1;
+        if reduced.get_pixel(x, y).data[0] > reduced.get_pixel(x + 1, y).data[0] {
+          hash |= 1;
+        }
+      }
+    }
+
+    self.perceptual_hashes.insert(source.clone(), hash);
+    Ok(hash)
+  }
+
+  /// Re-decodes every path-based image and its variants from their sources and re-uploads
+  /// them under the same keys. Only needed when the decode path changed behind already
+  /// uploaded images, e.g. after the R/B swizzle fix or a `set_premultiply` toggle. Skipped
+  /// are images loaded from raw data, whose bytes are not kept around, pipeline variants,
+  /// whose stages are not kept either, and banded images and atlases, which have to be
+  /// unloaded and built again. Generated checkerboards and masks are opaque and unaffected.
+  pub fn revalidate_all(&mut self) -> Result<RevalidationReport, Error> {
+    let mut report = RevalidationReport::default();
+    let mut resources = vec![];
+    let alpha_type = self.alpha_type();
+
+    let cache_keys: Vec<CacheKey> = self.uploads.images.keys().cloned().collect();
+    for cache_key in cache_keys {
+      if let ImageSource::Bundled(_) = cache_key.source {
+        report.skipped.push(Upload::Cached(cache_key));
+        continue;
+      }
+
+      let prepared = self.variant_pixels(&cache_key.source, cache_key.variant).and_then(|(image, partial)| {
+        let (data, descriptor) = self.prepare(image)?;
+        Ok((data, descriptor, partial))
+      });
+      let (data, descriptor, partial) = match prepared {
+        Ok(prepared) => prepared,
+        Err(ref error) if error.downcast_ref::<VariantNotReproducibleError>().is_some() => {
+          report.skipped.push(Upload::Cached(cache_key));
+          continue;
+        }
+        Err(error) => {
+          report.failed.push((Upload::Cached(cache_key), error));
+          continue;
+        }
+      };
+
+      let color_space = self.color_space_of(&cache_key.source);
+      // Hints set for the source take precedence over the premultiplication setting
+      let alpha_type = match self.sampling_hints.get(&cache_key.source) {
+        Some(sampling) if cache_key.variant == Variant::Original => sampling.alpha_type,
+        _ => alpha_type,
+      };
+      let info = self.uploads.images.get_mut(&cache_key).unwrap();
+      info.partial = partial;
+      info.color_space = color_space;
+      info.sampling.alpha_type = alpha_type;
+      resources.push(ResourceUpdate::UpdateImage(UpdateImage {
+        dirty_rect: DirtyRect::All,
+        key: info.key,
+        descriptor,
+        data,
+      }));
+
+      if info.descriptor != descriptor {
+        info.descriptor = descriptor;
+        report.descriptor_changed.push(Upload::Cached(cache_key.clone()));
+      }
+      report.reuploaded.push(Upload::Cached(cache_key));
+    }
+
+    let trimmed_sources: Vec<ImageSource> = self.uploads.trimmed_images.keys().cloned().collect();
+    for source in trimmed_sources {
+      let prepared = self.decode_for_upload(&source).and_then(|(image, _)| {
+        let (trimmed, rect) = trim_transparent(image.to_rgba());
+        let (data, descriptor) = self.prepare(trimmed)?;
+        Ok((data, descriptor, rect))
+      });
+      let (data, descriptor, rect) = match prepared {
+        Ok(prepared) => prepared,
+        Err(error) => {
+          report.failed.push((Upload::Trimmed(source), error));
+          continue;
+        }
+      };
+
+      let trimmed = self.uploads.trimmed_images.get_mut(&source).unwrap();
+      trimmed.0.sampling.alpha_type = alpha_type;
+      trimmed.1 = rect;
+      resources.push(ResourceUpdate::UpdateImage(UpdateImage {
+        dirty_rect: DirtyRect::All,
+        key: trimmed.0.key,
+        descriptor,
+        data,
+      }));
+
+      if trimmed.0.descriptor != descriptor {
+        trimmed.0.descriptor = descriptor;
+        report.descriptor_changed.push(Upload::Trimmed(source.clone()));
+      }
+      report.reuploaded.push(Upload::Trimmed(source));
+    }
+
+    report.skipped.extend(self.uploads.banded_images.keys().cloned().map(Upload::Banded));
+    report.skipped.extend(self.uploads.atlases.keys().cloned().map(Upload::Atlas));
+
+    if !resources.is_empty() {
+      self.render_api().update_resources(resources);
+    }
+
+    Ok(report)
+  }
+
+  // The pixels uploaded for a variant of the source and whether they are partial
+  fn variant_pixels(&self, source: &ImageSource, variant: Variant) -> Result<(DynamicImage, bool), Error> {
+    if let Variant::Pipeline(_) = variant {
+      return Err(VariantNotReproducibleError { variant }.into());
+    }
+
+    let (image, partial) = self.decode_for_upload(source)?;
+    let (width, height) = image.dimensions();
+    let image = match variant {
+      Variant::Original | Variant::Pipeline(_) => image,
+      Variant::Mip(level) => resize_alpha_weighted(&image, (width >> level).max(1), (height >> level).max(1)),
+      Variant::IntegerScaled(factor) => {
+        if factor == 0 || width.checked_mul(factor).and(height.checked_mul(factor)).is_none() {
+          bail!("Can't scale a {}x{} image by {}", width, height, factor);
+        }
+        scale_integer(&image, factor)
+      }
+      Variant::Graded(lut) => {
+        let mut rgba = image.to_rgba();
+        match self.color_luts.get(&lut) {
+          Some(color_lut) => color_lut.apply(&mut rgba),
+          None => return Err(LutMissingError { lut }.into()),
+        }
+        DynamicImage::ImageRgba8(rgba)
+      }
+    };
+    Ok((image, partial))
+  }
+
+  /// Inspects the headers of a file source to tell multi-frame GIF, APNG and WebP files from
+  /// still images, without decoding any pixels. Files are only read up to the second GIF frame
+  /// or the APNG animation chunk.
+  pub fn is_animated(&self, source: &ImageSource) -> Result<bool, Error> {
+    if let Some(bytes) = source.as_encoded() {
+      return Ok(is_animated_stream(bytes)?);
+    }
+    let animated = match self.resolve_source(source)? {
+      Some(bytes) => is_animated_stream(&bytes[..])?,
+      None => is_animated_stream(BufReader::new(File::open(self.source_path(source)?)?))?,
+    };
+    Ok(animated)
+  }
+
+  // Returns the image to upload and whether it was only partially decoded
+  fn decode_for_upload(&self, source: &ImageSource) -> Result<(DynamicImage, bool), Error> {
+    let (image, partial) = match self.decode_source(source) {
+      Ok(image) => (image, false),
+      // Only decoding errors can leave rows behind
+      Err(error) => match error.downcast_ref::<ImageError>().and_then(|_| self.decode_truncated(source)) {
+        Some(image) => (image, true),
+        None => return Err(error),
+      },
+    };
+
+    Ok((self.apply_source_options(source, image), partial))
+  }
+
+  // Applies the color space and alpha intent set for the source to its decoded pixels
+  fn apply_source_options(&self, source: &ImageSource, image: DynamicImage) -> DynamicImage {
+    let image = match self.color_spaces.get(source) {
+      Some(&ColorSpace::DisplayP3) => {
+        let mut rgba = image.to_rgba();
+        color_space::display_p3_to_srgb(&mut rgba);
+        DynamicImage::ImageRgba8(rgba)
+      }
+      _ => image,
+    };
+
+    match self.alpha_intents.get(source) {
+      // Dropping the alpha channel makes prepare_image fill it with 255 and mark the
+      // descriptor opaque without scanning
+      Some(&AlphaIntent::Opaque) => DynamicImage::ImageRgb8(image.to_rgb()),
+      _ => image,
+    }
+  }
+
+  // Recovers the rows a truncated PNG file still holds, when lenient decoding is on
+  fn decode_truncated(&self, source: &ImageSource) -> Option<DynamicImage> {
+    if !self.lenient_decoding {
+      return None;
+    }
+
+    let mut reader = self.source_reader(source).ok()?;
+    check_scanline_png(&mut reader).ok()?;
+    decode_rows(image::png::PNGDecoder::new(reader)).map(DynamicImage::ImageRgba8)
+  }
+
+  fn decode_source(&self, source: &ImageSource) -> Result<DynamicImage, Error> {
+    if let Some(bytes) = source.as_encoded() {
+      return Ok(image::load_from_memory(bytes)?);
+    }
+    match self.resolve_source(source)? {
+      Some(bytes) => Ok(image::load_from_memory(&bytes)?),
+      None => Ok(image::open(self.source_path(source)?)?),
+    }
+  }
+
+  // Reads the encoded source the same way decode_source finds it, for decoders reading it
+  // incrementally
+  fn source_reader<'s>(&self, source: &'s ImageSource) -> Result<Box<dyn ReadSeek + 's>, Error> {
+    if let Some(bytes) = source.as_encoded() {
+      return Ok(Box::new(Cursor::new(bytes)));
+    }
+    match self.resolve_source(source)? {
+      Some(bytes) => Ok(Box::new(Cursor::new(bytes))),
+      None => Ok(Box::new(BufReader::new(File::open(self.source_path(source)?)?))),
+    }
+  }
+
+  fn resolve_source(&self, source: &ImageSource) -> Result<Option<Vec<u8>>, Error> {
+    match self.source_resolver {
+      Some(ref resolver) => resolver(source),
+      None => Ok(None),
+    }
+  }
+
+  fn source_path(&self, source: &ImageSource) -> Result<PathBuf, Error> {
+    match *source {
+      ImageSource::AbsolutePath(ref path) => Ok(path.clone()),
+      ImageSource::AssetPath(ref relative_path) => {
+        let mut path = PathBuf::from(&self.assets_path);
+        path.push(relative_path);
+        Ok(path)
+      }
+      ImageSource::AssetWithRoot(ref root, ref relative_path) => {
+        let mut path = match self.asset_roots.get(root) {
+          Some(root_path) => root_path.clone(),
+          None => {
+            return Err(
+              AssetRootMissingError {
+                name: root.to_owned(),
+              }.into(),
+            )
+          }
+        };
+        path.push(relative_path);
+        Ok(path)
+      }
+      ImageSource::Bundled(ref name) => Err(
+        BundledImageMissingError {
+          name: name.to_owned(),
+        }.into(),
+      ),
+      #[cfg(feature = "bytes")]
+      ImageSource::Bytes(ref name, _) => Err(
+        NoSourcePathError {
+          name: name.to_owned(),
+        }.into(),
+      ),
+    }
+  }
+
+  // Uploads pixels decoded from the source, recording how they were decoded
+  fn create_source_resource(
+    &mut self,
+    source: &ImageSource,
+    data: ImageData,
+    descriptor: ImageDescriptor,
+    partial: bool,
+  ) -> ImageInfo {
+    let mut image_info = self.create_image_resource(data, descriptor);
+    image_info.partial = partial;
+    image_info.color_space = self.color_space_of(source);
+    image_info
+  }
+
+  fn put_image(
+    &mut self,
+    source: &ImageSource,
+    data: ImageData,
+    descriptor: ImageDescriptor,
+    partial: bool,
+  ) -> &ImageInfo {
+    let mut image_info = self.create_source_resource(source, data, descriptor, partial);
+    if let Some(&sampling) = self.sampling_hints.get(source) {
+      image_info.sampling = sampling;
+    }
+    let cache_key = CacheKey::original(source);
+    self.uploads.images.insert(cache_key.clone(), image_info);
+    self.tag_generation(source);
+    &self.uploads.images[&cache_key]
+  }
+
+  /// Writes the sources of the cached images and their variants to a manifest file, for
+  /// `warm_from_manifest` to reload on the next launch. Images loaded from memory, trimmed and
+  /// banded images are not recorded.
+  pub fn export_manifest<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+    let mut text = String::new();
+    for key in self.uploads.images.keys() {
+      let entry = match key.variant {
+        Variant::Original => ManifestEntry::Original(key.source.clone()),
+        Variant::Mip(level) => ManifestEntry::Mip(key.source.clone(), level),
+        Variant::IntegerScaled(factor) => ManifestEntry::IntegerScaled(key.source.clone(), factor),
+        // The stages are not serializable and identities don't carry over to the next run
+        Variant::Pipeline(_) => continue,
+        Variant::Graded(lut) => match self.lut_sources.iter().find(|&(_, &id)| id == lut) {
+          Some((lut_source, _)) => ManifestEntry::Graded(key.source.clone(), lut_source.clone()),
+          None => continue,
+        },
+      };
+      if let Some(line) = manifest::format_entry(&entry) {
+        writeln!(text, "{}", line).unwrap();
+      }
+    }
+
+    fs::write(path, text)?;
+    Ok(())
+  }
+
+  /// Loads every image recorded by `export_manifest`. Malformed lines and sources that no
+  /// longer load are skipped and listed in the report, only failing to read the file fails.
+  pub fn warm_from_manifest<P: AsRef<Path>>(&mut self, path: P) -> Result<ManifestWarmReport, Error> {
+    let text = fs::read_to_string(path)?;
+    let mut report = ManifestWarmReport::default();
+    for line in text.lines().filter(|line| !line.is_empty()) {
+      let entry = match manifest::parse_entry(line) {
+        Some(entry) => entry,
+        None => {
+          report.malformed.push(line.to_string());
+          continue;
+        }
+      };
+
+      let loaded = match entry {
+        ManifestEntry::Original(ref source) => self.get_image(source).map(|_| ()),
+        ManifestEntry::Mip(ref source, level) => self.get_image_mip(source, level).map(|_| ()),
+        ManifestEntry::IntegerScaled(ref source, factor) => {
+          self.get_image_integer_scaled(source, factor).map(|_| ())
+        }
+        ManifestEntry::Graded(ref source, ref lut_source) => self
+          .load_color_lut(lut_source)
+          .and_then(|lut| self.get_image_graded(source, lut))
+          .map(|_| ()),
+      };
+      match loaded {
+        Ok(()) => report.warmed += 1,
+        Err(error) => report.failed.push((entry, error)),
+      }
+    }
+    Ok(report)
+  }
+
+  /// Writes the source reduced to a palette of at most `max_colors` colors, between 2 and 256,
+  /// as an indexed image. The format is picked from the extension of `out`, `.png` for PNG-8 or
+  /// `.gif`, other extensions are refused.
+  pub fn save_image_quantized<P: AsRef<Path>>(
+    &self,
+    source: &ImageSource,
+    out: P,
+    max_colors: usize,
+  ) -> Result<(), Error> {
+    if !(2..=256).contains(&max_colors) {
+      bail!("Palettes hold between 2 and 256 colors, not {}", max_colors);
+    }
+
+    let out = out.as_ref();
+    let extension = out.extension().and_then(|extension| extension.to_str()).unwrap_or("");
+    let write: fn(&IndexedImage, File) -> Result<(), Error> = match &extension.to_ascii_lowercase()[..] {
+      "png" => quantize::write_png,
+      "gif" => quantize::write_gif,
+      _ => bail!("Quantized images are written as .png or .gif, not {}", out.display()),
+    };
+
+    let rgba = self.decode_source(source)?.to_rgba();
+    let indexed = quantize::quantize(&rgba, max_colors, self.dither_quantized);
+    write(&indexed, File::create(out)?)
+  }
+
+  /// The generation new loads are tagged with, starts at 0.
+  pub fn generation(&self) -> u64 {
+    self.generation
+  }
+
+  /// Starts a new generation, e.g. when the app switches screens, and returns it. Images keep
+  /// the generation they were first loaded in, later cache hits do not retag them.
+  pub fn bump_generation(&mut self) -> u64 {
+    self.generation += 1;
+    self.generation
+  }
+
+  /// Sources whose images were loaded in a generation older than `generation`.
+  pub fn loaded_before_generation<'a>(
+    &'a self,
+    generation: u64,
+  ) -> impl Iterator<Item = &'a ImageSource> + 'a {
+    self
+      .uploads
+      .generations
+      .iter()
+      .filter(move |&(_, &loaded_in)| loaded_in < generation)
+      .map(|(source, _)| source)
+  }
+
+  /// Unloads every source loaded in a generation older than `generation` and returns them.
+  pub fn unload_before_generation(&mut self, generation: u64) -> Vec<ImageSource> {
+    let sources: Vec<ImageSource> = self.loaded_before_generation(generation).cloned().collect();
+    for source in &sources {
+      self.unload_image(source);
+    }
+    sources
+  }
+
+  fn tag_generation(&mut self, source: &ImageSource) {
+    let generation = self.generation;
+    self.uploads.generations.entry(source.clone()).or_insert(generation);
+  }
+
+  /// Deletes the images uploaded for the source, returns false when nothing was loaded.
+  pub fn unload_image(&mut self, source: &ImageSource) -> bool {
+    self.staged_images.remove(source);
+    self.uploads.generations.remove(source);
+
+    let mut keys = vec![];
+    let variants: Vec<_> = self.uploads.images.keys().filter(|key| &key.source == source).cloned().collect();
+    for cache_key in variants {
+      keys.push(self.uploads.images.remove(&cache_key).unwrap().key);
+    }
+    if let Some((info, _)) = self.uploads.trimmed_images.remove(source) {
+      keys.push(info.key);
+    }
+    if let Some(banded) = self.uploads.banded_images.remove(source) {
+      keys.extend(banded.bands.iter().map(|info| info.key));
+    }
+    if let ImageSource::Bundled(ref name) = *source {
+      keys.extend(self.uploads.blob_images.remove(name).map(|info| info.key));
+    }
+
+    if keys.is_empty() {
+      return false;
+    }
+
+    self.delete_keys(keys);
+    true
+  }
+
+  /// Deletes every uploaded image in a single batch and drops prefetched ones.
+  pub fn clear(&mut self) {
+    self.staged_images.clear();
+    self.uploads.generations.clear();
+    let mut keys: Vec<ImageKey> = self.uploads.images.drain().map(|(_, info)| info.key).collect();
+    keys.extend(self.uploads.trimmed_images.drain().map(|(_, (info, _))| info.key));
+    keys.extend(self.uploads.checkerboards.drain().map(|(_, info)| info.key));
+    keys.extend(self.uploads.rounded_masks.drain().map(|(_, info)| info.key));
+    keys.extend(self.uploads.blob_images.drain().map(|(_, info)| info.key));
+    for (_, atlas) in self.uploads.atlases.drain() {
+      keys.extend(atlas.pages.iter().map(|info| info.key));
+    }
+    for (_, banded) in self.uploads.banded_images.drain() {
+      keys.extend(banded.bands.iter().map(|info| info.key));
+    }
+
+    if !keys.is_empty() {
+      self.delete_keys(keys);
+    }
+  }
+
+  /// Deletes every uploaded image of every window and drops all caches, pooled keys, counters
+  /// and settings, leaving the loader as freshly constructed with only the render APIs kept.
+  pub fn reset(&mut self) {
+    let active_window = self.active_window;
+    let windows: Vec<WindowId> = self.parked_windows.keys().cloned().collect();
+    for window in windows {
+      self.set_active_window(window);
+      self.clear();
+    }
+    self.set_active_window(active_window);
+    self.clear();
+
+    let render = self.uploads.render.take();
+    let mut parked_windows = HashMap::new();
+    for (window, uploads) in self.parked_windows.drain() {
+      let uploads = WindowUploads {
+        render: uploads.render,
+        ..WindowUploads::default()
+      };
+      parked_windows.insert(window, uploads);
+    }
+    // Blob handlers given to WebRender keep sharing the rasterizers
+    let blob_rasterizers = self.blob_rasterizers.clone();
+    *self = ImageLoader {
+      uploads: WindowUploads {
+        render,
+        ..WindowUploads::default()
+      },
+      active_window,
+      parked_windows,
+      blob_rasterizers,
+      ..ImageLoader::default()
+    };
+  }
+
+  /// Sets the render API of a window, the default window's being the one `init_resources`
+  /// sets. Register it before loading images for the window, keys generated by a previous API
+  /// are not valid with the new one.
+  pub fn register_render_api<A: ResourceApi + 'static>(&mut self, window: WindowId, render: A) {
+    if window == self.active_window {
+      self.uploads.render = Some(Box::new(render));
+    } else {
+      self.parked_windows.entry(window).or_default().render = Some(Box::new(render));
+    }
+  }
+
+  /// Switches the window `get_image` and every other upload goes to. Each window has its own
+  /// cache of uploaded images and keys, so an image loaded for one window is decoded and
+  /// uploaded again through the other window's API the first time it is asked for there.
+  /// Decoded data and analysis results (prefetched images, histograms, LUTs, ...) and all
+  /// settings are shared between windows. `unload_image`, `clear` and the stats, lookup and
+  /// eviction counters included, only cover the active window.
+  pub fn set_active_window(&mut self, window: WindowId) {
+    if window == self.active_window {
+      return;
+    }
+
+    let uploads = self.parked_windows.remove(&window).unwrap_or_default();
+    let parked = mem::replace(&mut self.uploads, uploads);
+    self.parked_windows.insert(self.active_window, parked);
+    self.active_window = window;
+  }
+
+  pub fn active_window(&self) -> WindowId {
+    self.active_window
+  }
+
+  fn delete_keys(&mut self, keys: Vec<ImageKey>) {
+    self.uploads.evictions += keys.len() as u64;
+    {
+      let mut rasterizers = self.blob_rasterizers.lock().unwrap();
+      for key in &keys {
+        rasterizers.remove(key);
+      }
+    }
+    let resources = keys.iter().map(|&key| ResourceUpdate::DeleteImage(key)).collect();
+    self.render_api().update_resources(resources);
+
+    let room = self.key_pool_capacity.saturating_sub(self.uploads.free_keys.len());
+    self.uploads.free_keys.extend(keys.into_iter().take(room));
+  }
+
+  pub fn create_image_resource(&mut self, data: ImageData, descriptor: ImageDescriptor) -> ImageInfo {
+    let (resource, image_info) = self.add_image_resource(data, descriptor);
+    self.render_api().update_resources(vec![resource]);
+    image_info
+  }
+
+  fn add_image_resource(
+    &mut self,
+    data: ImageData,
+    descriptor: ImageDescriptor,
+  ) -> (ResourceUpdate, ImageInfo) {
+    let key = match self.uploads.free_keys.pop() {
+      Some(key) => key,
+      None => self.render_api().generate_image_key(),
+    };
+    let resource = ResourceUpdate::AddImage(AddImage {
+      tiling: self.tiling_for(&descriptor),
+      descriptor,
+      data,
+      key,
+    });
+
+    let image_info = ImageInfo {
+      descriptor,
+      key,
+      partial: false,
+      color_space: ColorSpace::Srgb,
+      sampling: SamplingHints {
+        rendering: ImageRendering::Auto,
+        alpha_type: self.alpha_type(),
+      },
+    };
+
+    (resource, image_info)
+  }
+
+  /// Decodes the source on the calling thread into a staging area without touching the
+  /// render API, so the expensive decode can run in an idle phase. Staged images are
+  /// uploaded by `flush_prefetched`, or by the first `get_image` asking for them.
+  pub fn prefetch(&mut self, source: &ImageSource) -> Result<(), Error> {
+    let uploaded = self.uploads.images.contains_key(&CacheKey::original(source));
+    if uploaded || self.staged_images.contains_key(source) {
+      return Ok(());
+    }
+
+    let (image, partial) = self.decode_for_upload(source)?;
+    let (data, descriptor) = self.prepare(image)?;
+    self.staged_images.insert(source.clone(), (data, descriptor, partial));
+    Ok(())
+  }
+
+  /// Uploads every prefetched image in a single resource update.
+  pub fn flush_prefetched(&mut self) {
+    if self.staged_images.is_empty() {
+      return;
+    }
+
+    let staged: Vec<_> = self.staged_images.drain().collect();
+    let mut resources = Vec::with_capacity(staged.len());
+    let mut replaced = Vec::new();
+    for (source, (data, descriptor, partial)) in staged {
+      let (resource, mut image_info) = self.add_image_resource(data, descriptor);
+      image_info.partial = partial;
+      image_info.color_space = self.color_space_of(&source);
+      if let Some(&sampling) = self.sampling_hints.get(&source) {
+        image_info.sampling = sampling;
+      }
+      resources.push(resource);
+      self.tag_generation(&source);
+      if let Some(old) = self.uploads.images.insert(CacheKey::new(source, Variant::Original), image_info) {
+        replaced.push(old.key);
+      }
+    }
+
+    self.render_api().update_resources(resources);
+    if !replaced.is_empty() {
+      self.delete_keys(replaced);
+    }
+  }
+
+  pub fn update_texture(&mut self, key: ImageKey, descriptor: ImageDescriptor, data: ExternalImageData) {
+    let resource = ResourceUpdate::UpdateImage(UpdateImage {
+      data: ImageData::External(data),
+      dirty_rect: DirtyRect::All,
+      descriptor,
+      key,
+    });
+
+    self.render_api().update_resources(vec![resource]);
+
+    let ExternalImageData {
+      id: ExternalImageId(texture_id),
+      ..
+    } = data;
+
+    self.uploads.texture_descriptors.insert(texture_id, descriptor);
+  }
+
+  /// Registers a blob image under a name, replacing any blob of the same name. WebRender hands
+  /// the `commands` to the handler returned by `blob_handler`, which calls `rasterizer` for
+  /// the `tile_size` tiles that are visible only, at the resolution they are displayed at.
+  /// `unload_image` with a bundled source of the name deletes it.
+  pub fn add_blob_image(
+    &mut self,
+    name: &str,
+    descriptor: ImageDescriptor,
+    commands: Vec<u8>,
+    tile_size: TileSize,
+    rasterizer: Arc<dyn BlobRasterizer>,
+  ) -> ImageInfo {
+    if let Some(previous) = self.uploads.blob_images.remove(name) {
+      self.delete_keys(vec![previous.key]);
+    }
+
+    let (mut resource, image_info) = self.add_image_resource(ImageData::new_blob_image(commands), descriptor);
+    if let ResourceUpdate::AddImage(ref mut add) = resource {
+      add.tiling = Some(tile_size);
+    }
+    // Registered before the image reaches WebRender, so its first tiles find the rasterizer
+    self.blob_rasterizers.lock().unwrap().insert(image_info.key, rasterizer);
+    self.render_api().update_resources(vec![resource]);
+
+    self.tag_generation(&ImageSource::bundled(name));
+    self.uploads.blob_images.insert(name.to_owned(), image_info.clone());
+    image_info
+  }
+
+  /// The blob image handler to pass to WebRender in `RendererOptions`, one per window
+  /// renderer. Every handler dispatches to the rasterizers registered with `add_blob_image`.
+  pub fn blob_handler(&self) -> BlobHandler {
+    BlobHandler::new(self.blob_rasterizers.clone())
+  }
+
+  /// Replaces the commands of a blob image, the handler re-rasterizes the tiles touching
+  /// `dirty_rect`. Returns false when no blob image is registered under the name.
+  pub fn update_blob_image(&mut self, name: &str, commands: Vec<u8>, dirty_rect: DirtyRect) -> bool {
+    let (key, descriptor) = match self.uploads.blob_images.get(name) {
+      Some(info) => (info.key, info.descriptor),
+      None => return false,
+    };
+
+    let resource = ResourceUpdate::UpdateImage(UpdateImage {
+      data: ImageData::new_blob_image(commands),
+      dirty_rect,
+      descriptor,
+      key,
+    });
+    self.render_api().update_resources(vec![resource]);
+    true
+  }
+
+  /// Re-uploads new pixels for an image, limiting the update to the rectangle that differs
+  /// from its previous pixels. Returns the updated rect, `None` when nothing changed and no
+  /// update was sent.
+  pub fn update_image_data(
+    &mut self,
+    key: ImageKey,
+    descriptor: ImageDescriptor,
+    previous: &[u8],
+    data: Vec<u8>,
+  ) -> Option<DeviceIntRect> {
+    let rect = changed_rect(&descriptor, previous, &data)?;
+    let resource = ResourceUpdate::UpdateImage(UpdateImage {
+      data: ImageData::new(data),
+      dirty_rect: DirtyRect::Partial(rect),
+      descriptor,
+      key,
+    });
+
+    self.render_api().update_resources(vec![resource]);
+    Some(rect)
+  }
+
+  pub fn load_image(&mut self, name: &str, data: Vec<u8>) -> Result<(), Error> {
+    if let Err(error) = self.load_image_internal(name, data) {
+      bail!("Failed to load image from raw data {}", error);
+    }
+
+    Ok(())
+  }
+
+  fn load_image_internal(&mut self, name: &str, data: Vec<u8>) -> Result<(), Error> {
+    self.load_dynamic_image_internal(name, image::load_from_memory(&data)?)
+  }
+
+  /// Uploads an already decoded pixel buffer described by `layout`, converting it to the BGRA8
+  /// WebRender expects.
+  pub fn load_raw_image(
+    &mut self,
+    name: &str,
+    width: u32,
+    height: u32,
+    layout: PixelLayout,
+    data: &[u8],
+  ) -> Result<(), Error> {
+    if let Err(error) = self.load_raw_image_internal(name, width, height, layout, data) {
+      bail!("Failed to load image from raw pixels {}", error);
+    }
+
+    Ok(())
+  }
+
+  fn load_raw_image_internal(
+    &mut self,
+    name: &str,
+    width: u32,
+    height: u32,
+    layout: PixelLayout,
+    data: &[u8],
+  ) -> Result<(), Error> {
+    let image = DynamicImage::ImageRgba8(layout.to_rgba_image(width, height, data)?);
+    self.load_dynamic_image_internal(name, image)
+  }
+
+  /// Uploads an image decoded or produced with the image crate.
+  pub fn load_dynamic_image(&mut self, name: &str, image: DynamicImage) -> Result<(), Error> {
+    if let Err(error) = self.load_dynamic_image_internal(name, image) {
+      bail!("Failed to load image {}", error);
+    }
+
+    Ok(())
+  }
+
+  fn load_dynamic_image_internal(&mut self, name: &str, image: DynamicImage) -> Result<(), Error> {
+    let (data, descriptor) = self.prepare(image)?;
+    let image_info = self.create_image_resource(data, descriptor);
+    let source = ImageSource::bundled(name);
+    self.tag_generation(&source);
+    // A name loaded again replaces its image, the old upload would otherwise never be deleted
+    if let Some(old) = self.uploads.images.insert(CacheKey::new(source, Variant::Original), image_info) {
+      self.delete_keys(vec![old.key]);
+    }
+    Ok(())
+  }
+
+  /// Like `load_image`, but decodes straight from a seekable reader (a file, a `Cursor`, ...)
+  /// which lets decoders that jump around the stream avoid buffering the whole input.
+  pub fn load_image_from_seek<R: Read + Seek>(&mut self, name: &str, reader: R) -> Result<(), Error> {
+    if let Err(error) = self.load_image_from_seek_internal(name, reader) {
+      bail!("Failed to load image from reader {}", error);
+    }
+
+    Ok(())
+  }
+
+  fn load_image_from_seek_internal<R: Read + Seek>(
+    &mut self,
+    name: &str,
+    mut reader: R,
+  ) -> Result<(), Error> {
+    let format = guess_seekable_format(&mut reader)?;
+    let image = image::load(BufReader::new(reader), format)?;
+    self.load_dynamic_image_internal(name, image)
+  }
+
+  /// Decodes a tiny sample of each format so one-time decoder setup (lookup tables, inflate
+  /// state) is paid during a loading screen instead of on the first real image. JPEG and PNG
+  /// benefit the most. Samples are produced by the matching encoder, so formats the image
+  /// crate can only decode (WebP, TIFF, TGA, HDR) can't be warmed and are skipped. Returns the
+  /// formats that were warmed.
+  pub fn warm_decoders(&self, formats: &[image::ImageFormat]) -> Result<Vec<image::ImageFormat>, Error> {
+    let sample = DynamicImage::ImageRgba8(RgbaImage::new(1, 1));
+    let mut warmed = vec![];
+    for &format in formats {
+      let mut encoded = vec![];
+      match sample.write_to(&mut encoded, format) {
+        Err(ImageError::UnsupportedError(_)) => continue,
+        result => result?,
+      }
+      image::load_from_memory_with_format(&encoded, format)?;
+      warmed.push(format);
+    }
+
+    Ok(warmed)
+  }
+
+  pub fn stats(&self) -> ImageStats {
+    self.collect_stats(None)
+  }
+
+  /// Same as `stats`, scoped to the sources of one kind. Atlas pages and generated images are
+  /// not backed by a single source and only count towards the global `stats`, as do evictions.
+  pub fn stats_for_kind(&self, kind: SourceKind) -> ImageStats {
+    self.collect_stats(Some(kind))
+  }
+
+  pub fn loaded_sources<'a>(&'a self) -> impl Iterator<Item = &'a ImageSource> + 'a {
+    self
+      .uploads
+      .images
+      .keys()
+      .filter(|key| key.variant == Variant::Original)
+      .map(|key| &key.source)
+  }
+
+  pub fn loaded_sources_of_kind<'a>(
+    &'a self,
+    kind: SourceKind,
+  ) -> impl Iterator<Item = &'a ImageSource> + 'a {
+    self.loaded_sources().filter(move |source| source.kind() == kind)
+  }
+
+  fn collect_stats(&self, kind: Option<SourceKind>) -> ImageStats {
+    let matches = |source_kind| kind.map_or(true, |kind| kind == source_kind);
+
+    let mut stats = ImageStats::default();
+    if kind.is_none() {
+      stats.evictions = self.uploads.evictions;
+    }
+    for (&source_kind, counters) in &self.uploads.lookups {
+      if matches(source_kind) {
+        stats.cache_hits += counters.hits;
+        stats.cache_misses += counters.misses;
+      }
+    }
+
+    for (source, info) in self.resident_images() {
+      if kind.is_none() || source.map_or(false, |source| matches(source.kind())) {
+        stats.images += 1;
+        stats.resident_bytes += descriptor_bytes(&info.descriptor);
+      }
+    }
+
+    stats
+  }
+
+  /// Renders `stats` in the OpenMetrics text format, ready to be served from a `/metrics`
+  /// endpoint.
+  pub fn metrics_text(&self) -> String {
+    let stats = self.stats();
+    let metrics: [(&str, &str, &str, u64); 5] = [
+      ("resources_image_cache_hits", "counter", "Image lookups served from the cache.", stats.cache_hits),
+      ("resources_image_cache_misses", "counter", "Image lookups that had to decode.", stats.cache_misses),
+      ("resources_image_evictions", "counter", "Uploaded images deleted again.", stats.evictions),
+      ("resources_images", "gauge", "Images currently uploaded.", stats.images as u64),
+      ("resources_image_resident_bytes", "gauge", "Bytes of uploaded pixel data.", stats.resident_bytes),
+    ];
+
+    let mut text = String::new();
+    for &(name, kind, help, value) in metrics.iter() {
+      let sample = if kind == "counter" {
+        format!("{}_total", name)
+      } else {
+        name.to_string()
+      };
+
+      writeln!(text, "# TYPE {} {}", name, kind).unwrap();
+      writeln!(text, "# HELP {} {}", name, help).unwrap();
+      writeln!(text, "{} {}", sample, value).unwrap();
+    }
+    text.push_str("# EOF\n");
+    text
+  }
+
+  // Every uploaded image along with the source it was loaded from, if any
+  fn resident_images<'a>(&'a self) -> impl Iterator<Item = (Option<&'a ImageSource>, &'a ImageInfo)> + 'a {
+    let uploads = &self.uploads;
+    let images = uploads.images.iter().map(|(key, info)| (Some(&key.source), info));
+    let trimmed = uploads.trimmed_images.iter().map(|(source, &(ref info, _))| (Some(source), info));
+    let bands = uploads
+      .banded_images
+      .iter()
+      .flat_map(|(source, banded)| banded.bands.iter().map(move |info| (Some(source), info)));
+    let atlas_pages = uploads.atlases.values().flat_map(|atlas| atlas.pages.iter().map(|info| (None, info)));
+    let generated = uploads.checkerboards.values().chain(uploads.rounded_masks.values());
+    let generated = generated.map(|info| (None, info));
+    let blobs = uploads.blob_images.values().map(|info| (None, info));
+    images.chain(trimmed).chain(bands).chain(atlas_pages).chain(generated).chain(blobs)
+  }
+
+  fn color_space_of(&self, source: &ImageSource) -> ColorSpace {
+    self.color_spaces.get(source).cloned().unwrap_or_default()
+  }
+
+  fn check_deadline(&self, started: Instant) -> Result<(), Error> {
+    match self.load_deadline {
+      Some(deadline) if started.elapsed() > deadline => Err(LoadTimeoutError { deadline }.into()),
+      _ => Ok(()),
+    }
+  }
+
+  fn alpha_type(&self) -> AlphaType {
+    if self.straight_alpha {
+      AlphaType::Alpha
+    } else {
+      AlphaType::PremultipliedAlpha
+    }
+  }
+
+  fn prepare(&self, image: DynamicImage) -> Result<(ImageData, ImageDescriptor), Error> {
+    let (width, height) = image.dimensions();
+    let max_size = match self.max_texture_size {
+      Some(max_size) if width > max_size || height > max_size => max_size,
+      _ => return prepare_image(image, !self.straight_alpha),
+    };
+
+    match self.oversize_policy {
+      OversizePolicy::Error => Err(
+        TextureTooLargeError {
+          width,
+          height,
+          max_size,
+        }.into(),
+      ),
+      OversizePolicy::Downscale => {
+        let longest = u64::from(width.max(height));
+        let scaled_width = (u64::from(width) * u64::from(max_size) / longest).max(1) as u32;
+        let scaled_height = (u64::from(height) * u64::from(max_size) / longest).max(1) as u32;
+        let image = resize_alpha_weighted(&image, scaled_width, scaled_height);
+        prepare_image(image, !self.straight_alpha)
+      }
+      // Oversized images are uploaded with tiling, leaving the split to WebRender
+      OversizePolicy::Tile(_) => prepare_image(image, !self.straight_alpha),
+    }
+  }
+
+  fn tiling_for(&self, descriptor: &ImageDescriptor) -> Option<TileSize> {
+    match (self.oversize_policy, self.max_texture_size) {
+      (OversizePolicy::Tile(tile_size), Some(max_size)) => {
+        let size = descriptor.size;
+        if size.width as u32 > max_size || size.height as u32 > max_size {
+          Some(tile_size)
+        } else {
+          None
+        }
+      }
+      _ => None,
+    }
+  }
+
+  /// The id namespace of the active window's render API, `None` until it is initialized.
+  pub fn namespace(&self) -> Option<IdNamespace> {
+    self.uploads.render.as_ref().map(|api| api.get_namespace_id())
+  }
+
+  fn render_api(&self) -> &dyn ResourceApi {
+    &**self.uploads.render.as_ref().unwrap()
+  }
+}
+
+fn prepare_image(
+  image: DynamicImage,
+  premultiply_alpha: bool,
+) -> Result<(ImageData, ImageDescriptor), Error> {
+  let image_dims = image.dimensions();
+
+  // Every layout is converted to the upload buffer in a single pass over the decoded pixels,
+  // swizzling, premultiplying and checking opacity together. Grayscale and RGBA buffers are
+  // uploaded in place, grayscale as R8 masks. Images decoded without an alpha channel (JPEG,
+  // RGB PNG) are opaque by construction and skip the check.
+  let (format, bytes, opaque) = match image {
+    image::ImageLuma8(luma) => (ImageFormat::R8, luma.into_raw(), true),
+    image::ImageLumaA8(luma_alpha) => {
+      count_opacity_scan();
+      let mut bytes = Vec::with_capacity(luma_alpha.len() * 2);
+      let mut opaque = true;
+      for pixel in luma_alpha.chunks(2) {
+        let (value, alpha) = (pixel[0], pixel[1]);
+        let value = if premultiply_alpha { multiply_alpha(value, alpha) } else { value };
+        bytes.extend_from_slice(&[value, value, value, alpha]);
+        opaque = opaque && alpha == 255;
+      }
+      (ImageFormat::BGRA8, bytes, opaque)
+    }
+    image::ImageRgb8(rgb) => {
+      let mut bytes = Vec::with_capacity(rgb.len() / 3 * 4);
+      for pixel in rgb.chunks(3) {
+        bytes.extend_from_slice(&[pixel[2], pixel[1], pixel[0], 255]);
+      }
+      (ImageFormat::BGRA8, bytes, true)
+    }
+    image::ImageRgba8(rgba) => {
+      count_opacity_scan();
+      let mut bytes = rgba.into_raw();
+      let mut opaque = true;
+      for pixel in bytes.chunks_mut(4) {
+        pixel.swap(0, 2);
+        let alpha = pixel[3];
+        if premultiply_alpha && alpha != 255 {
+          for channel in pixel[..3].iter_mut() {
+            *channel = multiply_alpha(*channel, alpha);
+          }
+        }
+        opaque = opaque && alpha == 255;
+      }
+      (ImageFormat::BGRA8, bytes, opaque)
+    }
+  };
+
+  let descriptor = ImageDescriptor::new(image_dims.0 as i32, image_dims.1 as i32, format, opaque, false);
+  let data = ImageData::new(bytes);
+
+  Ok((data, descriptor))
+}
+
+#[cfg(test)]
+thread_local!(static OPACITY_SCANS: ::std::cell::Cell<usize> = ::std::cell::Cell::new(0));
+
+// Lets tests check which layouts pay for the opacity check
+fn count_opacity_scan() {
+  #[cfg(test)]
+  OPACITY_SCANS.with(|scans| scans.set(scans.get() + 1));
+}
+
+fn multiply_alpha(value: u8, alpha: u8) -> u8 {
+  ((u32::from(value) * u32::from(alpha) + 128) / 255) as u8
+}
+
+/// Resizes with the color weighted by alpha: filtering straight alpha lets the color of fully
+/// transparent pixels bleed into the edges of shapes, so the pixels are premultiplied for the
+/// filter and unpremultiplied again afterwards.
+pub fn resize_alpha_weighted(image: &DynamicImage, width: u32, height: u32) -> DynamicImage {
+  let mut rgba = match *image {
+    DynamicImage::ImageRgba8(ref rgba) => rgba.clone(),
+    DynamicImage::ImageLumaA8(_) => image.to_rgba(),
+    _ => return image.resize_exact(width, height, FilterType::Triangle),
+  };
+
+  premultiply(&mut rgba);
+  let mut resized = image::imageops::resize(&rgba, width, height, FilterType::Triangle);
+  unpremultiply(&mut resized);
+  DynamicImage::ImageRgba8(resized)
+}
+
+// Coverage of each pixel by the rounded rectangle, from the distance of its center to the arc
+// of the nearest corner. Pixels away from the corners measure against a zero-length offset
+// and are fully covered.
+fn rounded_rect_coverage(width: u32, height: u32, radius: u32) -> Vec<u8> {
+  let radius = radius.min(width.min(height) / 2) as f32;
+  let mut coverage = Vec::with_capacity((width * height) as usize);
+  for y in 0..height {
+    for x in 0..width {
+      let (px, py) = (x as f32 + 0.5, y as f32 + 0.5);
+      let dx = px - px.max(radius).min(width as f32 - radius);
+      let dy = py - py.max(radius).min(height as f32 - radius);
+      let distance = (dx * dx + dy * dy).sqrt() - radius;
+      let covered = if dx == 0.0 && dy == 0.0 {
+        1.0
+      } else {
+        (0.5 - distance).max(0.0).min(1.0)
+      };
+      coverage.push((covered * 255.0).round() as u8);
+    }
+  }
+  coverage
+}
+
+// Crops the image to the bounding box of its non-transparent pixels, returning the box in the
+// image. A fully transparent image yields a 1x1 transparent image and an empty rect.
+fn trim_transparent(rgba: RgbaImage) -> (DynamicImage, DeviceIntRect) {
+  let (width, height) = rgba.dimensions();
+  let (mut min_x, mut min_y, mut max_x, mut max_y) = (width, height, 0, 0);
+  for (x, y, pixel) in rgba.enumerate_pixels() {
+    if pixel.data[3] != 0 {
+      min_x = min_x.min(x);
+      min_y = min_y.min(y);
+      max_x = max_x.max(x + 1);
+      max_y = max_y.max(y + 1);
+    }
+  }
+
+  if min_x >= max_x || min_y >= max_y {
+    return (DynamicImage::ImageRgba8(RgbaImage::new(1, 1)), DeviceIntRect::zero());
+  }
+
+  let rect = DeviceIntRect::new(
+    DeviceIntPoint::new(min_x as i32, min_y as i32),
+    DeviceIntSize::new((max_x - min_x) as i32, (max_y - min_y) as i32),
+  );
+  let trimmed = DynamicImage::ImageRgba8(rgba).crop(min_x, min_y, max_x - min_x, max_y - min_y);
+  (trimmed, rect)
+}
+
+/// Replicates every pixel into a `factor` x `factor` block.
+pub fn scale_integer(image: &DynamicImage, factor: u32) -> DynamicImage {
+  fn replicate<P: Pixel + 'static>(
+    image: &ImageBuffer<P, Vec<P::Subpixel>>,
+    factor: u32,
+  ) -> ImageBuffer<P, Vec<P::Subpixel>> {
+    let (width, height) = image.dimensions();
+    ImageBuffer::from_fn(width * factor, height * factor, |x, y| *image.get_pixel(x / factor, y / factor))
+  }
+
+  match *image {
+    DynamicImage::ImageLuma8(ref luma) => DynamicImage::ImageLuma8(replicate(luma, factor)),
+    DynamicImage::ImageRgb8(ref rgb) => DynamicImage::ImageRgb8(replicate(rgb, factor)),
+    DynamicImage::ImageRgba8(ref rgba) => DynamicImage::ImageRgba8(replicate(rgba, factor)),
+    _ => DynamicImage::ImageRgba8(replicate(&image.to_rgba(), factor)),
+  }
+}
+
+/// Number of differing bits between two perceptual hashes.
+pub fn hamming_distance(a: u64, b: u64) -> u32 {
+  (a ^ b).count_ones()
+}
+
+/// Bounding rect of the pixels that differ between two buffers of the described image, the
+/// whole image when the buffer sizes don't match.
+pub fn changed_rect(descriptor: &ImageDescriptor, previous: &[u8], data: &[u8]) -> Option<DeviceIntRect> {
+  let size = descriptor.size;
+  let whole = DeviceIntRect::new(DeviceIntPoint::zero(), size);
+  let bytes_per_pixel = descriptor.format.bytes_per_pixel() as usize;
+  let stride = descriptor.stride.map_or(size.width as usize * bytes_per_pixel, |stride| stride as usize);
+  if previous.len() != data.len() || stride == 0 {
+    return Some(whole);
+  }
+
+  let row_len = size.width as usize * bytes_per_pixel;
+  let (mut min_x, mut min_y, mut max_x, mut max_y) = (usize::max_value(), usize::max_value(), 0, 0);
+  for (y, (old_row, new_row)) in previous.chunks(stride).zip(data.chunks(stride)).enumerate() {
+    let old_row = &old_row[..row_len.min(old_row.len())];
+    let new_row = &new_row[..row_len.min(new_row.len())];
+    let first = match old_row.iter().zip(new_row).position(|(a, b)| a != b) {
+      Some(first) => first,
+      None => continue,
+    };
+    let last = old_row.iter().zip(new_row).rposition(|(a, b)| a != b).unwrap();
+
+    min_x = min_x.min(first / bytes_per_pixel);
+    max_x = max_x.max(last / bytes_per_pixel + 1);
+    min_y = min_y.min(y);
+    max_y = max_y.max(y + 1);
+  }
+
+  if max_y == 0 {
+    return None;
+  }
+
+  Some(DeviceIntRect::new(
+    DeviceIntPoint::new(min_x as i32, min_y as i32),
+    DeviceIntSize::new((max_x - min_x) as i32, (max_y - min_y) as i32),
+  ))
+}
+
+fn band_to_image(color: ColorType, width: u32, rows: u32, bytes: Vec<u8>) -> Result<DynamicImage, Error> {
+  let image = match color {
+    ColorType::RGBA(8) => ImageBuffer::from_raw(width, rows, bytes).map(DynamicImage::ImageRgba8),
+    ColorType::RGB(8) => ImageBuffer::from_raw(width, rows, bytes).map(DynamicImage::ImageRgb8),
+    ColorType::Gray(8) => ImageBuffer::from_raw(width, rows, bytes).map(DynamicImage::ImageLuma8),
+    ColorType::GrayA(8) => ImageBuffer::from_raw(width, rows, bytes)
+      .map(|buffer| DynamicImage::ImageRgba8(DynamicImage::ImageLumaA8(buffer).to_rgba())),
+    _ => None,
+  };
+
+  match image {
+    Some(image) => Ok(image),
+    None => Err(ImageError::UnsupportedColor(color).into()),
+  }
+}
+
+// Reads scanlines until the decoder fails, rows that could not be read stay transparent
+fn decode_rows<D: ImageDecoder>(mut decoder: D) -> Option<RgbaImage> {
+  let (width, height) = decoder.dimensions().ok()?;
+  let color = decoder.colortype().ok()?;
+  let row_len = decoder.row_len().ok()?;
+  if row_len == 0 {
+    return None;
+  }
+
+  let mut bytes = vec![0; row_len * height as usize];
+  let mut rows = 0;
+  for row in bytes.chunks_mut(row_len) {
+    if decoder.read_scanline(row).is_err() {
+      break;
+    }
+    rows += 1;
+  }
+
+  if rows == 0 {
+    return None;
+  }
+
+  let mut rgba = band_to_image(color, width, height, bytes).ok()?.to_rgba();
+  for (_, y, pixel) in rgba.enumerate_pixels_mut() {
+    if y >= rows {
+      pixel.data[3] = 0;
+    }
+  }
+  Some(rgba)
+}
+
+trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+// Fails unless the reader holds a non-interlaced PNG, the only layout decoded row by row
+fn check_scanline_png<R: Read + Seek>(reader: &mut R) -> Result<(), Error> {
+  let format = guess_seekable_format(reader)?;
+  if format != image::ImageFormat::PNG {
+    let message = format!("{:?} can't be decoded by scanline", format);
+    return Err(ImageError::UnsupportedError(message).into());
+  }
+
+  // The interlace method is the last byte of the IHDR chunk, which always comes first
+  match peek_header(reader, 29)?.get(28) {
+    Some(&0) => Ok(()),
+    Some(_) => Err(ImageError::UnsupportedError("interlaced PNG can't be decoded by scanline".into()).into()),
+    None => Err(ImageError::NotEnoughData.into()),
+  }
+}
+
+// Reads the dimensions from the header for formats with a header-only decoder
+fn probe_header<R: Read + Seek>(mut reader: R) -> Result<Option<(u32, u32)>, Error> {
+  let format = guess_seekable_format(&mut reader)?;
+  let dimensions = match format {
+    image::ImageFormat::PNG => image::png::PNGDecoder::new(reader).dimensions()?,
+    image::ImageFormat::JPEG => image::jpeg::JPEGDecoder::new(reader).dimensions()?,
+    _ => return Ok(None),
+  };
+  Ok(Some(dimensions))
+}
+
+fn guess_seekable_format<R: Read + Seek>(reader: &mut R) -> Result<image::ImageFormat, Error> {
+  Ok(image::guess_format(&peek_header(reader, 16)?)?)
+}
+
+// Reads up to `len` bytes and seeks back to where the reader was
+fn peek_header<R: Read + Seek>(reader: &mut R, len: u64) -> Result<Vec<u8>, Error> {
+  let start = reader.seek(SeekFrom::Current(0))?;
+  let mut header = Vec::with_capacity(len as usize);
+  reader.by_ref().take(len).read_to_end(&mut header)?;
+  reader.seek(SeekFrom::Start(start))?;
+  Ok(header)
+}
+
+fn is_animated_stream<R: Read>(mut reader: R) -> io::Result<bool> {
+  let mut header = Vec::with_capacity(21);
+  reader.by_ref().take(21).read_to_end(&mut header)?;
+  let animated = if (header.starts_with(b"GIF87a") || header.starts_with(b"GIF89a")) && header.len() >= 13 {
+    gif_has_second_frame((&header[13..]).chain(reader), header[10])
+  } else if header.starts_with(b"\x89PNG\r\n\x1a\n") {
+    png_has_animation_control((&header[8..]).chain(reader))
+  } else if header.len() > 20 && &header[0..4] == b"RIFF" && &header[8..12] == b"WEBP" {
+    // Only the extended VP8X header can carry the animation flag
+    return Ok(&header[12..16] == b"VP8X" && header[20] & 0x02 != 0);
+  } else {
+    return Ok(false);
+  };
+
+  // A file ending before the answer is known is a still image
+  match animated {
+    Err(ref error) if error.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+    animated => animated,
+  }
+}
+
+// Walks the GIF block structure after the logical screen descriptor, skipping over the
+// compressed data, and stops as soon as a second image descriptor starts
+fn gif_has_second_frame<R: Read>(mut reader: R, screen_flags: u8) -> io::Result<bool> {
+  fn skip_sub_blocks<R: Read>(reader: &mut R) -> io::Result<()> {
+    loop {
+      match read_byte(reader)? {
+        0 => return Ok(()),
+        length => skip_bytes(reader, u64::from(length))?,
+      }
+    }
+  }
+  fn color_table_len(flags: u8) -> u64 {
+    if flags & 0x80 != 0 {
+      3 << ((flags & 0x07) + 1)
+    } else {
+      0
+    }
+  }
+
+  skip_bytes(&mut reader, color_table_len(screen_flags))?;
+  let mut frames = 0;
+  loop {
+    match read_byte(&mut reader)? {
+      0x21 => {
+        read_byte(&mut reader)?;
+        skip_sub_blocks(&mut reader)?;
+      }
+      0x2C => {
+        frames += 1;
+        if frames == 2 {
+          return Ok(true);
+        }
+        let mut descriptor = [0; 9];
+        reader.read_exact(&mut descriptor)?;
+        // The local color table and the LZW code size precede the image data
+        skip_bytes(&mut reader, color_table_len(descriptor[8]) + 1)?;
+        skip_sub_blocks(&mut reader)?;
+      }
+      _ => return Ok(false),
+    }
+  }
+}
+
+// APNG announces itself with an acTL chunk placed before the first IDAT
+fn png_has_animation_control<R: Read>(mut reader: R) -> io::Result<bool> {
+  let mut chunk = [0; 8];
+  loop {
+    reader.read_exact(&mut chunk)?;
+    match &chunk[4..] {
+      b"acTL" => {
+        let mut frames = [0; 4];
+        reader.read_exact(&mut frames)?;
+        return Ok(read_u32_be(&frames) > 1);
+      }
+      b"IDAT" => return Ok(false),
+      // Skips the chunk data and its CRC
+      _ => skip_bytes(&mut reader, u64::from(read_u32_be(&chunk)) + 4)?,
+    }
+  }
+}
+
+fn read_byte<R: Read>(reader: &mut R) -> io::Result<u8> {
+  let mut byte = [0];
+  reader.read_exact(&mut byte)?;
+  Ok(byte[0])
+}
+
+fn skip_bytes<R: Read>(reader: &mut R, count: u64) -> io::Result<()> {
+  if io::copy(&mut reader.by_ref().take(count), &mut io::sink())? < count {
+    return Err(io::ErrorKind::UnexpectedEof.into());
+  }
+  Ok(())
+}
+
+fn read_u32_be(bytes: &[u8]) -> u32 {
+  (u32::from(bytes[0]) << 24) | (u32::from(bytes[1]) << 16) | (u32::from(bytes[2]) << 8) | u32::from(bytes[3])
+}
+
+fn descriptor_bytes(descriptor: &ImageDescriptor) -> u64 {
+  let size = descriptor.size;
+  size.width as u64 * size.height as u64 * descriptor.format.bytes_per_pixel() as u64
+}
+
+// From webrender/wrench
+// These are slow. Gecko's gfx/2d/Swizzle.cpp has better versions
+pub fn premultiply(data: &mut [u8]) {
+  for pixel in data.chunks_mut(4) {
+    let a = u32::from(pixel[3]);
+    let r = u32::from(pixel[2]);
+    let g = u32::from(pixel[1]);
+    let b = u32::from(pixel[0]);
+
+    pixel[3] = a as u8;
+    pixel[2] = ((r * a + 128) / 255) as u8;
+    pixel[1] = ((g * a + 128) / 255) as u8;
+    pixel[0] = ((b * a + 128) / 255) as u8;
+  }
+}
+
+pub fn unpremultiply(data: &mut [u8]) {
+  for pixel in data.chunks_mut(4) {
+    let a = u32::from(pixel[3]);
+    if a == 0 {
+      continue;
+    }
+
+    for channel in pixel[..3].iter_mut() {
+      *channel = ((u32::from(*channel) * 255 + a / 2) / a).min(255) as u8;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::cell::Cell;
+  use std::sync::Mutex;
+
+  type Updates = Arc<Mutex<Vec<ResourceUpdate>>>;
+
+  // Hands out sequential keys and records every resource update instead of sending it
+  struct RecordingApi {
+    namespace: IdNamespace,
+    next_key: Cell<u32>,
+    updates: Updates,
+  }
+
+  impl ResourceApi for RecordingApi {
+    fn generate_image_key(&self) -> ImageKey {
+      self.next_key.set(self.next_key.get() + 1);
+      ImageKey::new(self.namespace, self.next_key.get())
+    }
+    fn update_resources(&self, resources: Vec<ResourceUpdate>) {
+      self.updates.lock().unwrap().extend(resources);
+    }
+    fn get_namespace_id(&self) -> IdNamespace {
+      self.namespace
+    }
+  }
+
+  fn record_uploads(loader: &mut ImageLoader, window: WindowId) -> Updates {
+    let updates = Updates::default();
+    let api = RecordingApi {
+      namespace: IdNamespace(window.0 + 1),
+      next_key: Cell::new(0),
+      updates: updates.clone(),
+    };
+    loader.register_render_api(window, api);
+    updates
+  }
+
+  // Keys, descriptors and pixels of the images added by the recorded updates
+  fn added_images(updates: &Updates) -> Vec<(ImageKey, ImageDescriptor, Vec<u8>)> {
+    let updates = updates.lock().unwrap();
+    let added = updates.iter().filter_map(|update| match *update {
+      ResourceUpdate::AddImage(ref add) => Some((add.key, add.descriptor, raw_bytes(&add.data))),
+      _ => None,
+    });
+    added.collect()
+  }
+
+  fn updated_keys(updates: &Updates) -> Vec<ImageKey> {
+    let updates = updates.lock().unwrap();
+    let updated = updates.iter().filter_map(|update| match *update {
+      ResourceUpdate::UpdateImage(ref update) => Some(update.key),
+      _ => None,
+    });
+    updated.collect()
+  }
+
+  fn info(key: u32, width: i32) -> ImageInfo {
+    ImageInfo {
+      key: ImageKey::new(IdNamespace(1), key),
+      descriptor: ImageDescriptor::new(width, 4, ImageFormat::BGRA8, true, false),
+      partial: false,
+      sampling: SamplingHints {
+        rendering: ImageRendering::Auto,
+        alpha_type: AlphaType::PremultipliedAlpha,
+      },
+      color_space: ColorSpace::Srgb,
+    }
+  }
+
+  #[test]
+  fn image_infos_compare_by_key_and_descriptor() {
+    assert_eq!(info(1, 4), info(1, 4));
+
+    let mut hinted = info(1, 4);
+    hinted.partial = true;
+    hinted.sampling.rendering = ImageRendering::Pixelated;
+    hinted.color_space = ColorSpace::DisplayP3;
+    assert_eq!(info(1, 4), hinted);
+
+    assert_ne!(info(1, 4), info(2, 4));
+    assert_ne!(info(1, 4), info(1, 8));
+    assert_ne!(info(1, 4), info(2, 8));
+  }
+
+  #[test]
+  fn premultiply_off_keeps_the_straight_colors() {
+    let rgba = RgbaImage::from_raw(1, 1, vec![200, 100, 50, 128]).unwrap();
+    let (data, descriptor) = prepare_image(DynamicImage::ImageRgba8(rgba.clone()), false).unwrap();
+    assert_eq!(raw_bytes(&data), vec![50, 100, 200, 128]);
+    assert!(!descriptor.is_opaque);
+
+    let (data, _) = prepare_image(DynamicImage::ImageRgba8(rgba), true).unwrap();
+    assert_eq!(raw_bytes(&data), vec![25, 50, 100, 128]);
+  }
+
+  fn raw_bytes(data: &ImageData) -> Vec<u8> {
+    match *data {
+      ImageData::Raw(ref bytes) => bytes.to_vec(),
+      _ => panic!("expected raw image data"),
+    }
+  }
+
+  // Serves the encoded image for every source, no file involved
+  fn loader_serving(encoded: Vec<u8>) -> ImageLoader {
+    let mut loader = ImageLoader::new();
+    loader.set_source_resolver(move |_| Ok(Some(encoded.clone())));
+    loader
+  }
+
+  fn png(width: u32, height: u32, color: ColorType, pixels: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![];
+    image::png::PNGEncoder::new(&mut encoded).encode(pixels, width, height, color).unwrap();
+    encoded
+  }
+
+  #[test]
+  fn alpha_histogram_counts_each_level() {
+    let pixels = [1, 2, 3, 0, 1, 2, 3, 255, 1, 2, 3, 255, 1, 2, 3, 128];
+    let mut loader = loader_serving(png(2, 2, ColorType::RGBA(8), &pixels));
+    let histogram = loader.alpha_histogram(&ImageSource::asset("alpha.png")).unwrap();
+    assert_eq!(histogram[0], 1);
+    assert_eq!(histogram[255], 2);
+    assert_eq!(histogram[128], 1);
+    assert_eq!(histogram.iter().sum::<u32>(), 4);
+  }
+
+  #[test]
+  fn revalidation_reuploads_every_variant() {
+    let pixels = [0, 0, 0, 0, 10, 20, 30, 255, 0, 0, 0, 0, 40, 50, 60, 255];
+    let mut loader = loader_serving(png(2, 2, ColorType::RGBA(8), &pixels));
+    let updates = record_uploads(&mut loader, WindowId::default());
+    let (icon, logo) = (ImageSource::asset("icon.png"), ImageSource::asset("logo.png"));
+
+    let mut keys = vec![loader.get_image(&icon).unwrap().key, loader.get_image(&logo).unwrap().key];
+    keys.push(loader.get_image_for_scale(&icon, 0.5).unwrap().key);
+    keys.push(loader.get_image_integer_scaled(&icon, 2).unwrap().key);
+    keys.push(loader.get_image_trimmed(&icon).unwrap().0.key);
+    let pipeline = Pipeline::new().then(::pipeline::Grayscale);
+    loader.get_image_with_pipeline(&icon, &pipeline).unwrap();
+    loader.load_dynamic_image("raw", DynamicImage::ImageRgba8(RgbaImage::new(1, 1))).unwrap();
+    updates.lock().unwrap().clear();
+
+    let report = loader.revalidate_all().unwrap();
+    let mut updated = updated_keys(&updates);
+    updated.sort();
+    keys.sort();
+    assert_eq!(updated, keys);
+    assert_eq!(report.reuploaded.len(), 5);
+    assert!(report.reuploaded.contains(&Upload::Trimmed(icon.clone())));
+    assert!(report.failed.is_empty());
+
+    let pipeline_key = CacheKey::new(icon, Variant::Pipeline(pipeline.identity()));
+    assert_eq!(report.skipped.len(), 2);
+    assert!(report.skipped.contains(&Upload::Cached(pipeline_key)));
+    assert!(report.skipped.contains(&Upload::Cached(CacheKey::original(&ImageSource::bundled("raw")))));
+  }
+
+  // A 2x1 8 bit grayscale baseline TIFF, uncompressed in a single strip
+  fn gray_tiff(pixels: [u8; 2]) -> Vec<u8> {
+    let entries: [(u16, u16, u32); 9] = [
+      (256, 3, 2),
+      (257, 3, 1),
+      (258, 3, 8),
+      (259, 3, 1),
+      (262, 3, 1),
+      (273, 4, 122),
+      (277, 3, 1),
+      (278, 3, 1),
+      (279, 4, 2),
+    ];
+    let mut tiff = b"II\x2a\x00\x08\x00\x00\x00".to_vec();
+    tiff.extend_from_slice(&[entries.len() as u8, 0]);
+    for &(tag, kind, value) in entries.iter() {
+      tiff.extend_from_slice(&[tag as u8, (tag >> 8) as u8, kind as u8, 0, 1, 0, 0, 0]);
+      tiff.extend_from_slice(&[value as u8, (value >> 8) as u8, 0, 0]);
+    }
+    tiff.extend_from_slice(&[0; 4]);
+    tiff.extend_from_slice(&pixels);
+    tiff
+  }
+
+  #[test]
+  fn tiff_loads_from_a_cursor() {
+    let mut loader = ImageLoader::new();
+    let updates = record_uploads(&mut loader, WindowId::default());
+    loader.load_image_from_seek("scan", Cursor::new(gray_tiff([0x40, 0xc0]))).unwrap();
+
+    let added = added_images(&updates);
+    assert_eq!(added.len(), 1);
+    assert_eq!(added[0].1.size, DeviceIntSize::new(2, 1));
+    assert_eq!(added[0].2, vec![0x40, 0xc0]);
+    assert!(loader.uploads.images.contains_key(&CacheKey::original(&ImageSource::bundled("scan"))));
+  }
+
+  #[test]
+  fn opaque_layouts_skip_the_opacity_scan() {
+    let scans = || OPACITY_SCANS.with(|scans| scans.get());
+    let before = scans();
+    let rgb = DynamicImage::ImageRgb8(ImageBuffer::from_raw(1, 1, vec![1, 2, 3]).unwrap());
+    let (_, descriptor) = prepare_image(rgb, true).unwrap();
+    assert!(descriptor.is_opaque);
+    assert_eq!(scans(), before);
+
+    let rgba = DynamicImage::ImageRgba8(RgbaImage::from_raw(1, 1, vec![1, 2, 3, 255]).unwrap());
+    let (_, descriptor) = prepare_image(rgba, true).unwrap();
+    assert!(descriptor.is_opaque);
+    assert_eq!(scans(), before + 1);
+  }
+
+  #[test]
+  fn rgba_and_gray_decodes_upload_their_own_buffer() {
+    let rgba = RgbaImage::from_raw(2, 1, vec![1, 2, 3, 255, 4, 5, 6, 128]).unwrap();
+    let decoded = rgba.as_ptr();
+    let (data, descriptor) = prepare_image(DynamicImage::ImageRgba8(rgba), true).unwrap();
+    assert_eq!(descriptor.format, ImageFormat::BGRA8);
+    match data {
+      ImageData::Raw(ref bytes) => assert_eq!(bytes.as_ptr(), decoded),
+      _ => panic!("decoded images upload raw bytes"),
+    }
+
+    // Grayscale stays a single channel mask
+    let luma = ImageBuffer::from_raw(2, 1, vec![10, 20]).unwrap();
+    let decoded = luma.as_ptr();
+    let (data, descriptor) = prepare_image(DynamicImage::ImageLuma8(luma), true).unwrap();
+    assert_eq!((descriptor.format, descriptor.is_opaque), (ImageFormat::R8, true));
+    match data {
+      ImageData::Raw(ref bytes) => assert_eq!((bytes.as_ptr(), bytes.to_vec()), (decoded, vec![10, 20])),
+      _ => panic!("decoded images upload raw bytes"),
+    }
+  }
+
+  // A fresh directory for the files of one test
+  fn temp_dir(test: &str) -> PathBuf {
+    let dir = ::std::env::temp_dir().join(format!("resources-{}-{}", ::std::process::id(), test));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  #[test]
+  fn asset_roots_resolve_the_same_path_separately() {
+    let dir = temp_dir("asset_roots");
+    for &(root, value) in [("base", 10u8), ("theme", 200u8)].iter() {
+      fs::create_dir_all(dir.join(root)).unwrap();
+      fs::write(dir.join(root).join("icon.png"), png(1, 1, ColorType::Gray(8), &[value])).unwrap();
+    }
+
+    let mut loader = ImageLoader::new();
+    let updates = record_uploads(&mut loader, WindowId::default());
+    loader.register_asset_root("base", dir.join("base"));
+    loader.register_asset_root("theme", dir.join("theme"));
+    let base = loader.get_image(&ImageSource::asset_with_root("base", "icon.png")).unwrap().key;
+    let theme = loader.get_image(&ImageSource::asset_with_root("theme", "icon.png")).unwrap().key;
+    assert_ne!(base, theme);
+
+    let added = added_images(&updates);
+    assert_eq!(added[0].2, vec![10]);
+    assert_eq!(added[1].2, vec![200]);
+    assert!(loader.get_image(&ImageSource::asset_with_root("missing", "icon.png")).is_err());
+  }
+
+  #[test]
+  fn warming_skips_formats_without_an_encoder() {
+    let formats = [image::ImageFormat::PNG, image::ImageFormat::WEBP];
+    let warmed = ImageLoader::new().warm_decoders(&formats).unwrap();
+    assert_eq!(warmed, vec![image::ImageFormat::PNG]);
+  }
+
+  #[test]
+  fn trimming_a_centered_square_keeps_its_offset() {
+    let mut pixels = vec![0; 4 * 4 * 4];
+    for &(x, y) in [(1, 1), (2, 1), (1, 2), (2, 2)].iter() {
+      pixels[(y * 4 + x) * 4 + 3] = 255;
+    }
+    let mut loader = loader_serving(png(4, 4, ColorType::RGBA(8), &pixels));
+    let updates = record_uploads(&mut loader, WindowId::default());
+
+    let (info, rect) = loader.get_image_trimmed(&ImageSource::asset("square.png")).unwrap();
+    assert_eq!(rect, DeviceIntRect::new(DeviceIntPoint::new(1, 1), DeviceIntSize::new(2, 2)));
+    assert_eq!(info.descriptor.size, DeviceIntSize::new(2, 2));
+    assert_eq!(added_images(&updates)[0].2.len(), 2 * 2 * 4);
+  }
+
+  #[test]
+  fn trimming_a_transparent_image_yields_an_empty_rect() {
+    let (trimmed, rect) = trim_transparent(RgbaImage::new(3, 3));
+    assert_eq!(trimmed.dimensions(), (1, 1));
+    assert_eq!(rect, DeviceIntRect::zero());
+  }
+
+  #[test]
+  fn metrics_count_evictions_in_valid_lines() {
+    let mut loader = loader_serving(png(1, 1, ColorType::Gray(8), &[0]));
+    record_uploads(&mut loader, WindowId::default());
+    let (icon, logo) = (ImageSource::asset("icon.png"), ImageSource::asset("logo.png"));
+    loader.get_image(&icon).unwrap();
+    loader.get_image_for_scale(&icon, 0.5).unwrap();
+    loader.get_image(&logo).unwrap();
+    loader.unload_image(&icon);
+    loader.get_image(&icon).unwrap();
+    loader.clear();
+    assert_eq!(loader.stats().evictions, 4);
+
+    let text = loader.metrics_text();
+    let mut lines = text.lines().peekable();
+    let mut kind = None;
+    while let Some(line) = lines.next() {
+      let fields: Vec<&str> = line.splitn(4, ' ').collect();
+      match fields[..] {
+        ["#", "EOF"] => assert!(lines.peek().is_none()),
+        ["#", "TYPE", name, metric_kind] => {
+          assert!(metric_kind == "counter" || metric_kind == "gauge");
+          kind = Some((name, metric_kind));
+        }
+        ["#", "HELP", name, _] => assert_eq!(kind.map(|kind| kind.0), Some(name)),
+        [sample, value] => {
+          let (name, metric_kind) = kind.take().unwrap();
+          let suffix = if metric_kind == "counter" { "_total" } else { "" };
+          assert_eq!(sample, format!("{}{}", name, suffix));
+          assert!(sample.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'));
+          value.parse::<u64>().unwrap();
+        }
+        _ => panic!("malformed metric line {:?}", line),
+      }
+    }
+    assert!(text.ends_with("# EOF\n"));
+    assert!(text.contains("\nresources_image_evictions_total 4\n"));
+  }
+
+  #[test]
+  fn reloading_a_name_deletes_the_replaced_image() {
+    let mut loader = ImageLoader::new();
+    let updates = record_uploads(&mut loader, WindowId::default());
+    loader.load_image("icon", png(1, 1, ColorType::Gray(8), &[0])).unwrap();
+    let first = loader.get_image(&ImageSource::bundled("icon")).unwrap().key;
+    loader.load_image("icon", png(1, 1, ColorType::Gray(8), &[255])).unwrap();
+    assert!(loader.get_image(&ImageSource::bundled("icon")).unwrap().key != first);
+
+    let deleted: Vec<_> = updates
+      .lock()
+      .unwrap()
+      .iter()
+      .filter_map(|update| match *update {
+        ResourceUpdate::DeleteImage(key) => Some(key),
+        _ => None,
+      })
+      .collect();
+    assert_eq!(deleted, vec![first]);
+    assert_eq!(loader.stats().evictions, 1);
+  }
+
+  #[test]
+  fn pooled_keys_are_reused_after_their_deletion() {
+    let mut loader = loader_serving(png(1, 1, ColorType::Gray(8), &[0]));
+    let updates = record_uploads(&mut loader, WindowId::default());
+    loader.set_key_pool_capacity(8);
+    let key = loader.get_image(&ImageSource::asset("icon.png")).unwrap().key;
+    loader.unload_image(&ImageSource::asset("icon.png"));
+    assert_eq!(loader.get_image(&ImageSource::asset("icon.png")).unwrap().key, key);
+
+    let updates = updates.lock().unwrap();
+    assert_eq!(updates.len(), 3);
+    let deleted = match updates[1] {
+      ResourceUpdate::DeleteImage(key) => key,
+      _ => panic!("expected the deletion to be queued before the reuse"),
+    };
+    let reused = match updates[2] {
+      ResourceUpdate::AddImage(ref add) => add.key,
+      _ => panic!("expected the key to be reused by the reload"),
+    };
+    assert_eq!((deleted, reused), (key, key));
+  }
+
+  #[test]
+  fn keys_beyond_the_pool_capacity_are_dropped() {
+    let mut loader = loader_serving(png(1, 1, ColorType::Gray(8), &[0]));
+    let updates = record_uploads(&mut loader, WindowId::default());
+    loader.set_key_pool_capacity(1);
+    let (icon, logo) = (ImageSource::asset("icon.png"), ImageSource::asset("logo.png"));
+    let icon_key = loader.get_image(&icon).unwrap().key;
+    let logo_key = loader.get_image(&logo).unwrap().key;
+    loader.unload_image(&icon);
+    loader.unload_image(&logo);
+    assert_eq!(loader.uploads.free_keys, vec![icon_key]);
+
+    let deleted = updates
+      .lock()
+      .unwrap()
+      .iter()
+      .filter(|update| match **update {
+        ResourceUpdate::DeleteImage(key) => key == icon_key || key == logo_key,
+        _ => false,
+      })
+      .count();
+    assert_eq!(deleted, 2);
+
+    assert_eq!(loader.get_image(&logo).unwrap().key, icon_key);
+    let key = loader.get_image(&icon).unwrap().key;
+    assert!(key != icon_key && key != logo_key);
+
+    loader.unload_image(&icon);
+    loader.set_key_pool_capacity(0);
+    assert!(loader.uploads.free_keys.is_empty());
+  }
+
+  #[test]
+  fn source_accessors_match_their_variant() {
+    let absolute = ImageSource::absolute("/icons/close.png");
+    let asset = ImageSource::asset("close.png");
+    let rooted = ImageSource::asset_with_root("theme", "close.png");
+    let bundled = ImageSource::bundled("close");
+
+    assert_eq!(absolute.as_path(), Some(Path::new("/icons/close.png")));
+    assert_eq!(asset.as_path(), Some(Path::new("close.png")));
+    assert_eq!(rooted.as_path(), Some(Path::new("close.png")));
+    assert_eq!(bundled.as_path(), None);
+
+    assert_eq!(rooted.asset_root(), Some("theme"));
+    for source in [&absolute, &asset, &bundled].iter() {
+      assert_eq!(source.asset_root(), None);
+    }
+
+    assert_eq!(bundled.as_bundled_name(), Some("close"));
+    for source in [&absolute, &asset, &rooted].iter() {
+      assert_eq!(source.as_bundled_name(), None);
+    }
+  }
+
+  // An identity Hald CLUT of the given level, the color cube sampled in `level^2` steps
+  fn identity_hald(level: u32) -> Vec<u8> {
+    hald(level, &|color| color)
+  }
+
+  // Hald CLUT PNG whose entries hold `map` of the color they stand for
+  fn hald(level: u32, map: &dyn Fn([u8; 3]) -> [u8; 3]) -> Vec<u8> {
+    let (size, steps) = (level * level * level, level * level);
+    let mut pixels = vec![];
+    for index in 0..size * size {
+      let mut color = [0; 3];
+      let steps_taken = [index % steps, index / steps % steps, index / steps / steps];
+      for (channel, &step) in steps_taken.iter().enumerate() {
+        color[channel] = (step * 255 / (steps - 1)) as u8;
+      }
+      pixels.extend_from_slice(&map(color));
+    }
+    png(size, size, ColorType::RGB(8), &pixels)
+  }
+
+  #[test]
+  fn reset_leaves_every_collection_empty() {
+    let dir = temp_dir("reset");
+    fs::create_dir_all(dir.join("sprites")).unwrap();
+    let pixels = [0, 0, 0, 0, 10, 20, 30, 255, 0, 0, 0, 0, 40, 50, 60, 255];
+    fs::write(dir.join("icon.png"), png(2, 2, ColorType::RGBA(8), &pixels)).unwrap();
+    fs::write(dir.join("sprites").join("dot.png"), png(2, 2, ColorType::RGBA(8), &pixels)).unwrap();
+    fs::write(dir.join("lut.png"), identity_hald(2)).unwrap();
+
+    let mut loader = ImageLoader::new();
+    record_uploads(&mut loader, WindowId::default());
+    record_uploads(&mut loader, WindowId(1));
+    loader.assets_path = dir.clone();
+    let (icon, lut) = (ImageSource::asset("icon.png"), ImageSource::asset("lut.png"));
+    let staged = ImageSource::asset("sprites/dot.png");
+    loader.set_key_pool_capacity(8);
+    loader.treat_alpha_as(&staged, AlphaIntent::Opaque);
+    loader.set_color_space(&staged, ColorSpace::DisplayP3);
+    loader.register_dimensions(&staged, 2, 2);
+    loader.bump_generation();
+
+    loader.get_image(&icon).unwrap();
+    loader.get_image_for_scale(&icon, 0.5).unwrap();
+    let lut_id = loader.load_color_lut(&lut).unwrap();
+    loader.get_image_graded(&icon, lut_id).unwrap();
+    loader.get_image_trimmed(&icon).unwrap();
+    loader.get_image_banded(&icon, 1).unwrap();
+    loader.transparency_checkerboard(4).unwrap();
+    loader.rounded_rect_mask(8, 8, 2).unwrap();
+    loader.build_atlas_from_dir(&dir.join("sprites"), 16, 1).unwrap();
+    loader.alpha_histogram(&icon).unwrap();
+    loader.perceptual_hash(&icon).unwrap();
+    loader.average_luminance(&icon).unwrap();
+    loader.prefetch(&staged).unwrap();
+    loader.set_active_window(WindowId(1));
+    loader.get_image(&icon).unwrap();
+    loader.set_active_window(WindowId::default());
+    loader.get_image(&icon).unwrap();
+    assert!(!loader.staged_images.is_empty() && loader.stats().images > 0);
+
+    loader.reset();
+    assert!(loader.uploads.images.is_empty() && loader.uploads.texture_descriptors.is_empty());
+    assert!(loader.staged_images.is_empty() && loader.uploads.banded_images.is_empty());
+    assert!(loader.color_luts.is_empty() && loader.lut_sources.is_empty());
+    assert!(loader.uploads.atlases.is_empty() && loader.uploads.trimmed_images.is_empty());
+    assert!(loader.uploads.checkerboards.is_empty() && loader.uploads.rounded_masks.is_empty());
+    assert!(loader.uploads.blob_images.is_empty() && loader.blob_rasterizers.lock().unwrap().is_empty());
+    assert!(loader.alpha_histograms.is_empty() && loader.perceptual_hashes.is_empty());
+    assert!(loader.average_luminances.is_empty() && loader.alpha_intents.is_empty());
+    assert!(loader.registered_dimensions.is_empty() && loader.color_spaces.is_empty());
+    assert!(loader.dimension_mismatches.is_empty() && loader.sampling_hints.is_empty());
+    assert!(loader.uploads.free_keys.is_empty() && loader.key_pool_capacity == 0);
+    assert!(loader.uploads.generations.is_empty() && loader.uploads.lookups.is_empty());
+    assert_eq!((loader.generation(), loader.stats()), (0, ImageStats::default()));
+
+    assert!(loader.namespace().is_some());
+    loader.set_active_window(WindowId(1));
+    assert!(loader.uploads.images.is_empty() && loader.namespace().is_some());
+  }
+
+  // A 1x1 GIF with the given number of frames
+  fn gif(frames: usize) -> Vec<u8> {
+    let mut gif = b"GIF89a\x01\x00\x01\x00\x80\x00\x00".to_vec();
+    gif.extend_from_slice(&[0, 0, 0, 255, 255, 255]);
+    for _ in 0..frames {
+      gif.extend_from_slice(&[0x2c, 0, 0, 0, 0, 1, 0, 1, 0, 0]);
+      gif.extend_from_slice(&[2, 2, 0x4c, 0x01, 0]);
+    }
+    gif.push(0x3b);
+    gif
+  }
+
+  #[test]
+  fn only_multi_frame_images_are_animated() {
+    let source = ImageSource::asset("image");
+    assert!(loader_serving(gif(2)).is_animated(&source).unwrap());
+    assert!(!loader_serving(gif(1)).is_animated(&source).unwrap());
+    assert!(!loader_serving(png(1, 1, ColorType::Gray(8), &[0])).is_animated(&source).unwrap());
+  }
+
+  // Fails every read, so a check can only succeed if it stops before reaching it
+  struct Unreadable;
+
+  impl Read for Unreadable {
+    fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+      Err(io::ErrorKind::Other.into())
+    }
+  }
+
+  #[test]
+  fn animation_checks_stop_at_the_second_frame() {
+    let mut frames = gif(2);
+    frames.pop();
+    assert!(is_animated_stream((&frames[..]).chain(Unreadable)).unwrap());
+
+    let mut apng = b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR".to_vec();
+    apng.extend_from_slice(&[0; 17]);
+    apng.extend_from_slice(b"\x00\x00\x00\x08acTL\x00\x00\x00\x02");
+    assert!(is_animated_stream((&apng[..]).chain(Unreadable)).unwrap());
+
+    let dir = temp_dir("animated");
+    fs::write(dir.join("spinner.gif"), gif(2)).unwrap();
+    let mut loader = ImageLoader::new();
+    loader.assets_path = dir.clone();
+    assert!(loader.is_animated(&ImageSource::asset("spinner.gif")).unwrap());
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn prefetching_uploads_only_on_flush() {
+    let mut loader = loader_serving(png(1, 1, ColorType::Gray(8), &[0]));
+    let updates = record_uploads(&mut loader, WindowId::default());
+    let (icon, logo) = (ImageSource::asset("icon.png"), ImageSource::asset("logo.png"));
+    loader.prefetch(&icon).unwrap();
+    loader.prefetch(&logo).unwrap();
+    assert!(updates.lock().unwrap().is_empty());
+    assert!(loader.uploads.images.is_empty());
+
+    loader.flush_prefetched();
+    assert_eq!(added_images(&updates).len(), 2);
+    let key = loader.get_image(&icon).unwrap().key;
+    assert_eq!(added_images(&updates).len(), 2);
+    assert!(added_images(&updates).iter().any(|added| added.0 == key));
+  }
+
+  #[test]
+  fn oversize_policies() {
+    let source = ImageSource::asset("wide.png");
+    let oversized = |policy| {
+      let mut loader = loader_serving(png(8, 2, ColorType::Gray(8), &[0; 16]));
+      let updates = record_uploads(&mut loader, WindowId::default());
+      loader.set_max_texture_size(4);
+      loader.set_oversize_policy(policy);
+      let size = loader.get_image(&source).map(|info| info.descriptor.size);
+      let updates = updates.lock().unwrap();
+      let mut tiling = updates.iter().filter_map(|update| match *update {
+        ResourceUpdate::AddImage(ref add) => add.tiling,
+        _ => None,
+      });
+      (size.ok(), tiling.next())
+    };
+
+    assert_eq!(oversized(OversizePolicy::Error), (None, None));
+    assert_eq!(oversized(OversizePolicy::Downscale), (Some(DeviceIntSize::new(4, 1)), None));
+    assert_eq!(oversized(OversizePolicy::Tile(2)), (Some(DeviceIntSize::new(8, 2)), Some(2)));
+  }
+
+  #[test]
+  fn stats_and_sources_filter_by_kind() {
+    let mut loader = loader_serving(png(2, 1, ColorType::Gray(8), &[0, 0]));
+    record_uploads(&mut loader, WindowId::default());
+    let (asset, absolute) = (ImageSource::asset("icon.png"), ImageSource::absolute("/logo.png"));
+    loader.get_image(&asset).unwrap();
+    loader.get_image(&asset).unwrap();
+    loader.get_image(&absolute).unwrap();
+    loader.load_dynamic_image("raw", DynamicImage::ImageLuma8(ImageBuffer::new(1, 1))).unwrap();
+
+    let assets: Vec<&ImageSource> = loader.loaded_sources_of_kind(SourceKind::AssetPath).collect();
+    assert_eq!(assets, vec![&asset]);
+    let asset_stats = loader.stats_for_kind(SourceKind::AssetPath);
+    assert_eq!((asset_stats.images, asset_stats.cache_hits, asset_stats.cache_misses), (1, 1, 1));
+    assert_eq!(asset_stats.resident_bytes, 2);
+    assert_eq!(loader.stats_for_kind(SourceKind::Bundled).images, 1);
+    assert_eq!(loader.stats_for_kind(SourceKind::AssetWithRoot), ImageStats::default());
+    assert_eq!(loader.stats().images, 3);
+  }
+
+  #[test]
+  fn every_raw_layout_uploads_the_same_pixel() {
+    use pixel_layout::{AlphaKind, BitDepth, ChannelOrder};
+
+    let mut loader = ImageLoader::new();
+    let updates = record_uploads(&mut loader, WindowId::default());
+    let orders = [
+      (ChannelOrder::Rgba, [0, 1, 2, 3]),
+      (ChannelOrder::Bgra, [2, 1, 0, 3]),
+      (ChannelOrder::Argb, [1, 2, 3, 0]),
+      (ChannelOrder::Abgr, [3, 2, 1, 0]),
+    ];
+    // Both encodings of the pixel round-trip exactly through premultiplication
+    let alphas = [(AlphaKind::Straight, [255, 50, 100, 51]), (AlphaKind::Premultiplied, [51, 10, 20, 51])];
+    for &(order, offsets) in orders.iter() {
+      for &(alpha, rgba) in alphas.iter() {
+        for &depth in [BitDepth::Eight, BitDepth::Sixteen].iter() {
+          let layout = PixelLayout::new(order, alpha, depth);
+          let mut channels = [0u8; 4];
+          for (&offset, &value) in offsets.iter().zip(rgba.iter()) {
+            channels[offset] = value;
+          }
+          let data: Vec<u8> = match depth {
+            BitDepth::Eight => channels.to_vec(),
+            BitDepth::Sixteen => channels.iter().flat_map(|&value| vec![value, value]).collect(),
+          };
+          loader.load_raw_image("pixel", 1, 1, layout, &data).unwrap();
+        }
+      }
+    }
+
+    let added = added_images(&updates);
+    assert_eq!(added.len(), 16);
+    for &(_, descriptor, ref bytes) in &added {
+      assert_eq!(descriptor.format, ImageFormat::BGRA8);
+      assert_eq!(*bytes, vec![20, 10, 51, 51]);
+    }
+  }
+
+  #[test]
+  fn band_buffers_follow_the_band_height() {
+    let dir = temp_dir("band_buffers");
+    let path = dir.join("tall.png");
+    let pixels: Vec<u8> = (0..4 * 12).flat_map(|pixel| vec![pixel as u8, 0, 0, 255]).collect();
+    fs::write(&path, png(4, 12, ColorType::RGBA(8), &pixels)).unwrap();
+
+    let mut loader = ImageLoader::new();
+    let updates = record_uploads(&mut loader, WindowId::default());
+    let source = ImageSource::absolute(&path);
+    for &(band_height, bands) in [(1, 12), (5, 3), (12, 1)].iter() {
+      updates.lock().unwrap().clear();
+      let banded = loader.get_image_banded(&source, band_height).unwrap();
+      assert_eq!(banded.bands.len(), bands);
+
+      let added = added_images(&updates);
+      let largest = added.iter().map(|added| added.2.len()).max().unwrap();
+      assert_eq!(largest, 4 * 4 * band_height as usize);
+      assert!(added.iter().all(|added| added.1.size.height <= band_height as i32));
+    }
+  }
+
+  #[test]
+  fn banding_refuses_jpeg_and_interlaced_png() {
+    let dir = temp_dir("band_formats");
+    let jpeg = dir.join("photo.jpg");
+    fs::write(&jpeg, [0xff, 0xd8, 0xff, 0xe0, 0, 16, b'J', b'F', b'I', b'F', 0, 1, 1, 0, 0, 1]).unwrap();
+    let interlaced = dir.join("interlaced.png");
+    let mut encoded = png(2, 2, ColorType::Gray(8), &[0, 1, 2, 3]);
+    encoded[28] = 1;
+    fs::write(&interlaced, encoded).unwrap();
+
+    let mut loader = ImageLoader::new();
+    record_uploads(&mut loader, WindowId::default());
+    for path in &[jpeg, interlaced] {
+      let error = loader.get_image_banded(&ImageSource::absolute(path), 1).unwrap_err();
+      match error.downcast_ref::<ImageError>() {
+        Some(&ImageError::UnsupportedError(_)) => {}
+        _ => panic!("unexpected error {}", error),
+      }
+    }
+  }
+
+  #[test]
+  fn near_identical_images_hash_close_together() {
+    let hash = |noise: &dyn Fn(u32, u32) -> f32, invert: bool| {
+      let mut pixels = vec![];
+      for y in 0..64 {
+        for x in 0..72 {
+          let wave = 128.0 + 100.0 * (x as f32 * 0.15 + y as f32 * 0.1).sin() + noise(x, y);
+          let value = wave.round() as u8;
+          pixels.push(if invert { 255 - value } else { value });
+        }
+      }
+      let mut loader = loader_serving(png(72, 64, ColorType::Gray(8), &pixels));
+      loader.perceptual_hash(&ImageSource::asset("wave.png")).unwrap()
+    };
+
+    let original = hash(&|_, _| 0.0, false);
+    // Small deterministic errors, as a lossy re-encode would add
+    let recompressed = hash(&|x, y| ((x * 31 + y * 17) % 5) as f32 - 2.0, false);
+    let inverted = hash(&|_, _| 0.0, true);
+    assert!(hamming_distance(original, recompressed) <= 10);
+    assert!(hamming_distance(original, inverted) > 10);
+  }
+
+  #[test]
+  fn opaque_intent_forces_the_alpha_bytes() {
+    let pixels = [10, 20, 30, 7, 40, 50, 60, 0, 70, 80, 90, 200, 1, 2, 3, 128];
+    let mut loader = loader_serving(png(2, 2, ColorType::RGBA(8), &pixels));
+    let updates = record_uploads(&mut loader, WindowId::default());
+    let source = ImageSource::asset("noise.png");
+    loader.treat_alpha_as(&source, AlphaIntent::Opaque);
+
+    let descriptor = loader.get_image(&source).unwrap().descriptor;
+    assert!(descriptor.is_opaque);
+    let added = added_images(&updates);
+    assert!(added[0].2.chunks(4).all(|pixel| pixel[3] == 255));
+    assert_eq!(added[0].2[..3], [30, 20, 10]);
+
+    // Analyses see the same pixels as the upload
+    assert_eq!(loader.alpha_histogram(&source).unwrap()[255], 4);
+  }
+
+  #[test]
+  fn grading_through_identity_and_known_luts() {
+    let photo = png(2, 1, ColorType::RGB(8), &[200, 100, 50, 0, 128, 255]);
+    let identity = identity_hald(2);
+    let swapped = hald(2, &|[r, g, b]| [b, g, r]);
+    let mut loader = ImageLoader::new();
+    loader.set_source_resolver(move |source| {
+      Ok(Some(match *source {
+        ImageSource::AssetPath(ref path) if path.ends_with("identity.png") => identity.clone(),
+        ImageSource::AssetPath(ref path) if path.ends_with("swapped.png") => swapped.clone(),
+        _ => photo.clone(),
+      }))
+    });
+    let updates = record_uploads(&mut loader, WindowId::default());
+
+    let source = ImageSource::asset("photo.png");
+    let identity = loader.load_color_lut(&ImageSource::asset("identity.png")).unwrap();
+    let swapped = loader.load_color_lut(&ImageSource::asset("swapped.png")).unwrap();
+    loader.get_image(&source).unwrap();
+    loader.get_image_graded(&source, identity).unwrap();
+    loader.get_image_graded(&source, swapped).unwrap();
+
+    // BGRA uploads, the swapped table exchanges red and blue
+    let added = added_images(&updates);
+    assert_eq!(added[0].2, vec![50, 100, 200, 255, 255, 128, 0, 255]);
+    assert_eq!(added[1].2, added[0].2);
+    assert_eq!(added[2].2, vec![200, 100, 50, 255, 0, 128, 255, 255]);
+  }
+
+  #[test]
+  fn truncated_png_recovers_only_when_lenient() {
+    let dir = temp_dir("truncated");
+    let pixels: Vec<u8> = (0..8).flat_map(|pixel| vec![pixel * 10, 0, 0, 255]).collect();
+    let mut encoded = png(2, 4, ColorType::RGBA(8), &pixels);
+    // Cuts the IEND chunk, the IDAT checksums and the last row
+    let truncated_len = encoded.len() - 12 - 4 - 4 - 9;
+    encoded.truncate(truncated_len);
+    let path = dir.join("partial.png");
+    fs::write(&path, &encoded).unwrap();
+    let mut interlaced = encoded.clone();
+    interlaced[28] = 1;
+    let interlaced_path = dir.join("interlaced.png");
+    fs::write(&interlaced_path, interlaced).unwrap();
+
+    let mut loader = ImageLoader::new();
+    let updates = record_uploads(&mut loader, WindowId::default());
+    let source = ImageSource::absolute(&path);
+    assert!(loader.get_image(&source).is_err());
+
+    loader.set_lenient_decoding(true);
+    assert!(loader.get_image(&source).unwrap().partial);
+    let added = added_images(&updates);
+    let alphas: Vec<u8> = added[0].2.chunks(4).map(|pixel| pixel[3]).collect();
+    assert_eq!(alphas, vec![255, 255, 255, 255, 255, 255, 0, 0]);
+
+    assert!(loader.get_image(&ImageSource::absolute(&interlaced_path)).is_err());
+  }
+
+  #[test]
+  fn sampling_hints_are_kept_for_later_lookups() {
+    let mut loader = loader_serving(png(1, 1, ColorType::Gray(8), &[90]));
+    let updates = record_uploads(&mut loader, WindowId::default());
+    record_uploads(&mut loader, WindowId(1));
+    let (icon, logo) = (ImageSource::asset("icon.png"), ImageSource::asset("logo.png"));
+    let sampling = SamplingHints {
+      rendering: ImageRendering::Pixelated,
+      alpha_type: AlphaType::Alpha,
+    };
+
+    let key = loader.get_image(&icon).unwrap().key;
+    assert_eq!(loader.get_image_with_sampling(&icon, sampling).unwrap().sampling, sampling);
+    let plain = loader.get_image(&icon).unwrap().clone();
+    assert_eq!((plain.key, plain.sampling), (key, sampling));
+    assert_eq!(added_images(&updates).len(), 1);
+    assert_eq!(loader.get_image(&logo).unwrap().sampling.rendering, ImageRendering::Auto);
+
+    loader.set_active_window(WindowId(1));
+    assert_eq!(loader.get_image(&icon).unwrap().sampling, sampling);
+  }
+
+  #[test]
+  fn atlas_packs_known_icons_with_a_gutter() {
+    let dir = temp_dir("atlas_icons");
+    let icons = [
+      ("wide.png", 8, 8, 10u8),
+      ("tall.png", 2, 6, 20),
+      ("small.png", 4, 4, 30),
+      ("big.png", 20, 20, 40),
+    ];
+    for &(name, width, height, value) in icons.iter() {
+      let pixels = vec![value; (width * height) as usize];
+      fs::write(dir.join(name), png(width, height, ColorType::Gray(8), &pixels)).unwrap();
+    }
+    fs::write(dir.join("notes.txt"), "not an image").unwrap();
+
+    let mut loader = ImageLoader::new();
+    let updates = record_uploads(&mut loader, WindowId::default());
+    loader.set_max_texture_size(12);
+    assert!(loader.build_atlas_from_dir(&dir, 16, 1).is_err());
+    loader.set_max_texture_size(32);
+    let report = loader.build_atlas_from_dir(&dir, 16, 1).unwrap();
+    assert_eq!(report.skipped, vec![dir.join("notes.txt")]);
+
+    let entries = report.entries;
+    assert_eq!(entries.len(), 4);
+    for &(name, width, height, _) in icons.iter() {
+      assert_eq!(entries[name].1.size, DeviceIntSize::new(width as i32, height as i32));
+    }
+    let page_key = entries["wide.png"].0;
+    assert_eq!(entries["tall.png"].0, page_key);
+    assert_eq!(entries["small.png"].0, page_key);
+    assert_eq!(entries["big.png"].1.origin, DeviceIntPoint::zero());
+
+    // Packed sprites keep at least a gutter apart
+    let packed = ["wide.png", "tall.png", "small.png"];
+    let rects: Vec<DeviceIntRect> = packed.iter().map(|name| entries[*name].1).collect();
+    let before = |a: &DeviceIntRect, b: &DeviceIntRect| {
+      a.origin.x + a.size.width < b.origin.x || a.origin.y + a.size.height < b.origin.y
+    };
+    for (index, a) in rects.iter().enumerate() {
+      for b in &rects[index + 1..] {
+        assert!(before(a, b) || before(b, a), "{:?} and {:?} touch", a, b);
+      }
+    }
+
+    // The gutter repeats the edge pixels
+    let page = added_images(&updates).into_iter().find(|added| added.0 == page_key).unwrap();
+    let wide = entries["wide.png"].1;
+    let page_width = page.1.size.width as usize;
+    let offset = |x: i32, y: i32| (y as usize * page_width + x as usize) * 4;
+    assert_eq!(page.2[offset(wide.origin.x - 1, wide.origin.y - 1)], 10);
+    assert_eq!(page.2[offset(wide.origin.x + wide.size.width, wide.origin.y + 2)], 10);
+  }
+
+  #[test]
+  fn atlas_gutters_offset_sprites_by_their_width() {
+    let dir = temp_dir("atlas_gutters");
+    fs::write(dir.join("square.png"), png(4, 4, ColorType::Gray(8), &[10; 16])).unwrap();
+    fs::write(dir.join("strip.png"), png(4, 2, ColorType::Gray(8), &[20; 8])).unwrap();
+
+    for &(gutter, strip_x, boundary) in [(0, 4, 4), (2, 10, 8)].iter() {
+      let mut loader = ImageLoader::new();
+      let updates = record_uploads(&mut loader, WindowId::default());
+      let entries = loader.build_atlas_from_dir(&dir, 16, gutter).unwrap().entries;
+      let origin = gutter as i32;
+      assert_eq!(entries["square.png"].1.origin, DeviceIntPoint::new(origin, origin));
+      assert_eq!(entries["strip.png"].1.origin, DeviceIntPoint::new(strip_x, origin));
+
+      // The square's gutter ends right where the strip's begins
+      let page = added_images(&updates).remove(0);
+      let offset = |x: usize, y: usize| (y * page.1.size.width as usize + x) * 4;
+      assert_eq!(page.2[offset(0, 0)], 10);
+      assert_eq!(page.2[offset(boundary - 1, gutter as usize)], 10);
+      assert_eq!(page.2[offset(boundary, gutter as usize)], 20);
+    }
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn slow_loads_trip_the_deadline() {
+    let encoded = png(1, 1, ColorType::Gray(8), &[0]);
+    let mut loader = ImageLoader::new();
+    loader.set_source_resolver(move |_| {
+      ::std::thread::sleep(Duration::from_millis(20));
+      Ok(Some(encoded.clone()))
+    });
+    let updates = record_uploads(&mut loader, WindowId::default());
+    loader.set_load_deadline(Duration::from_millis(1));
+
+    let error = loader.get_image(&ImageSource::asset("slow.png")).unwrap_err();
+    assert!(error.to_string().contains("deadline"), "{}", error);
+    assert!(added_images(&updates).is_empty());
+
+    loader.set_load_deadline(Duration::from_secs(60));
+    assert!(loader.get_image(&ImageSource::asset("slow.png")).is_ok());
+  }
+
+  #[test]
+  fn namespace_comes_from_the_render_api() {
+    let mut loader = ImageLoader::new();
+    assert_eq!(loader.namespace(), None);
+    record_uploads(&mut loader, WindowId::default());
+    assert_eq!(loader.namespace(), Some(IdNamespace(1)));
+
+    // Follows the active window
+    record_uploads(&mut loader, WindowId(4));
+    assert_eq!(loader.namespace(), Some(IdNamespace(1)));
+    loader.set_active_window(WindowId(4));
+    assert_eq!(loader.namespace(), Some(IdNamespace(5)));
+  }
+
+  #[test]
+  fn display_p3_tags_every_upload_of_the_source() {
+    let dir = temp_dir("display_p3");
+    let path = dir.join("wide.png");
+    let pixels: Vec<u8> = (0..16).flat_map(|_| vec![100, 150, 200, 255]).collect();
+    fs::write(&path, png(4, 4, ColorType::RGBA(8), &pixels)).unwrap();
+    fs::write(dir.join("lut.png"), identity_hald(2)).unwrap();
+
+    let mut loader = ImageLoader::new();
+    let updates = record_uploads(&mut loader, WindowId::default());
+    let srgb = ImageSource::absolute(&path);
+    loader.get_image(&srgb).unwrap();
+    let p3 = ImageSource::AssetWithRoot("wide gamut".to_owned(), PathBuf::from("wide.png"));
+    loader.register_asset_root("wide gamut", dir.clone());
+    loader.set_color_space(&p3, ColorSpace::DisplayP3);
+
+    let lut = loader.load_color_lut(&ImageSource::absolute(dir.join("lut.png"))).unwrap();
+    let mut infos = vec![
+      loader.get_image(&p3).unwrap().clone(),
+      loader.get_image_for_scale(&p3, 0.5).unwrap(),
+      loader.get_image_graded(&p3, lut).unwrap(),
+      loader.get_image_trimmed(&p3).unwrap().0,
+    ];
+    infos.extend(loader.get_image_banded(&p3, 2).unwrap().bands);
+    assert!(infos.iter().all(|info| info.color_space == ColorSpace::DisplayP3));
+
+    // The pixels are gamut mapped before upload
+    let added = added_images(&updates);
+    assert_eq!(loader.get_image(&srgb).unwrap().color_space, ColorSpace::Srgb);
+    assert_ne!(added[0].2[..4], added[1].2[..4]);
+    assert!(added[1..].iter().all(|upload| upload.2[..4] == added[1].2[..4]));
+  }
+
+  #[test]
+  fn a_corner_change_updates_only_its_pixel() {
+    let mut loader = ImageLoader::new();
+    let updates = record_uploads(&mut loader, WindowId::default());
+    let canvas = info(1, 8);
+    let previous = vec![0u8; 8 * 4 * 4];
+    let mut data = previous.clone();
+    let corner = data.len() - 4;
+    data[corner] = 255;
+
+    assert_eq!(loader.update_image_data(canvas.key, canvas.descriptor, &previous, previous.clone()), None);
+    assert!(updated_keys(&updates).is_empty());
+
+    let rect = loader.update_image_data(canvas.key, canvas.descriptor, &previous, data).unwrap();
+    assert_eq!(rect, DeviceIntRect::new(DeviceIntPoint::new(7, 3), DeviceIntSize::new(1, 1)));
+    let updates = updates.lock().unwrap();
+    match updates[..] {
+      [ResourceUpdate::UpdateImage(ref update)] => assert_eq!(update.dirty_rect, DirtyRect::Partial(rect)),
+      _ => panic!("expected a single update, got {} resources", updates.len()),
+    }
+  }
+
+  #[test]
+  fn rgba_images_round_trip_through_the_loader() {
+    let pixels = [200, 100, 50, 255, 200, 100, 50, 128, 10, 20, 30, 1, 0, 0, 0, 0];
+    let mut loader = loader_serving(png(2, 2, ColorType::RGBA(8), &pixels));
+    let updates = record_uploads(&mut loader, WindowId::default());
+    let source = ImageSource::asset("round trip.png");
+
+    let rgba = loader.as_rgba_image(&source).unwrap();
+    assert_eq!(&rgba[..], &pixels[..]);
+    loader.get_image(&source).unwrap();
+    loader.load_dynamic_image("round trip", DynamicImage::ImageRgba8(rgba)).unwrap();
+
+    let added = added_images(&updates);
+    assert_eq!(added[0].1, added[1].1);
+    assert_eq!(added[0].2, added[1].2);
+  }
+
+  #[test]
+  fn quarter_scale_uploads_the_quarter_size_level() {
+    let pixels = vec![128; 64 * 32];
+    let mut loader = loader_serving(png(64, 32, ColorType::Gray(8), &pixels));
+    let updates = record_uploads(&mut loader, WindowId::default());
+    let source = ImageSource::asset("large.png");
+
+    let quarter = loader.get_image_for_scale(&source, 0.25).unwrap();
+    assert_eq!(quarter.descriptor.size, DeviceIntSize::new(16, 8));
+    let added = added_images(&updates);
+    assert_eq!(added.len(), 1);
+    assert_eq!(added[0].1.size, DeviceIntSize::new(16, 8));
+
+    // A 2x display needs the half size level for the same layout size
+    loader.set_device_pixel_ratio(2.0);
+    let half = loader.get_image_for_scale(&source, 0.25).unwrap();
+    assert_eq!(half.descriptor.size, DeviceIntSize::new(32, 16));
+  }
+
+  #[test]
+  fn banding_and_recovery_read_resolved_sources() {
+    let mut encoded = png(2, 4, ColorType::Gray(8), &[1, 2, 3, 4, 5, 6, 7, 8]);
+    let mut loader = loader_serving(encoded.clone());
+    record_uploads(&mut loader, WindowId::default());
+    let banded = loader.get_image_banded(&ImageSource::asset("served.png"), 2).unwrap();
+    assert_eq!(banded.bands.len(), 2);
+
+    // Without the IEND chunk, the IDAT checksums and the last row
+    let truncated_len = encoded.len() - 12 - 4 - 4 - 3;
+    encoded.truncate(truncated_len);
+    let mut loader = loader_serving(encoded);
+    record_uploads(&mut loader, WindowId::default());
+    loader.set_lenient_decoding(true);
+    assert!(loader.get_image(&ImageSource::asset("served.png")).unwrap().partial);
+  }
+
+  #[test]
+  fn registered_dimensions_answer_probes_until_the_load() {
+    // Undecodable bytes, any probe reaching them would fail
+    let mut loader = loader_serving(b"not an image yet".to_vec());
+    record_uploads(&mut loader, WindowId::default());
+    let source = ImageSource::asset("manifest.png");
+    loader.register_dimensions(&source, 640, 480);
+    assert_eq!(loader.probe_dimensions(&source).unwrap(), (640, 480));
+    assert!(loader.take_dimension_mismatches().is_empty());
+
+    let mut loader = loader_serving(png(3, 2, ColorType::Gray(8), &[0; 6]));
+    record_uploads(&mut loader, WindowId::default());
+    loader.register_dimensions(&source, 640, 480);
+    loader.get_image(&source).unwrap();
+    let mismatch = DimensionMismatch {
+      source: source.clone(),
+      registered: (640, 480),
+      decoded: (3, 2),
+    };
+    assert_eq!(loader.take_dimension_mismatches(), vec![mismatch]);
+    assert!(loader.take_dimension_mismatches().is_empty());
+    assert_eq!(loader.probe_dimensions(&source).unwrap(), (3, 2));
+  }
+
+  #[cfg(feature = "bytes")]
+  #[test]
+  fn bytes_sources_load_like_vectors() {
+    let encoded = png(2, 1, ColorType::RGBA(8), &[200, 100, 50, 128, 1, 2, 3, 255]);
+
+    let mut shared = ImageLoader::new();
+    let shared_updates = record_uploads(&mut shared, WindowId::default());
+    let source = ImageSource::bytes("logo", Bytes::from(encoded.clone()));
+    let from_bytes = shared.get_image(&source).unwrap().clone();
+
+    let mut owned = ImageLoader::new();
+    let owned_updates = record_uploads(&mut owned, WindowId::default());
+    owned.load_image("logo", encoded).unwrap();
+    let from_vec = owned.get_image(&ImageSource::bundled("logo")).unwrap().clone();
+
+    assert_eq!(from_bytes, from_vec);
+    assert_eq!(added_images(&shared_updates)[0].2, added_images(&owned_updates)[0].2);
+  }
+
+  #[test]
+  fn unloading_old_generations_keeps_newer_images() {
+    let mut loader = loader_serving(png(1, 1, ColorType::Gray(8), &[7]));
+    let updates = record_uploads(&mut loader, WindowId::default());
+    let splash = ImageSource::asset("splash.png");
+    let (menu, game) = (ImageSource::asset("menu.png"), ImageSource::asset("game.png"));
+    loader.get_image(&splash).unwrap();
+    loader.bump_generation();
+    let menu_key = loader.get_image(&menu).unwrap().key;
+    // A cache hit in a later generation keeps the original tag
+    loader.get_image(&splash).unwrap();
+    let current = loader.bump_generation();
+    loader.get_image(&game).unwrap();
+
+    assert_eq!(loader.unload_before_generation(current - 1), vec![splash.clone()]);
+    assert!(!loader.uploads.images.contains_key(&CacheKey::original(&splash)));
+    assert_eq!(loader.get_image(&menu).unwrap().key, menu_key);
+    assert!(loader.uploads.images.contains_key(&CacheKey::original(&game)));
+    assert_eq!(added_images(&updates).len(), 3);
+  }
+
+  #[test]
+  fn blob_tiles_reach_the_registered_rasterizer() {
+    use blob::BlobRasterizer;
+    use webrender::api::{
+      BlobImageDescriptor, BlobImageError, BlobImageHandler, BlobImageParams, BlobImageRequest,
+      BlobImageResult, DevicePoint, RasterizedBlobImage, TileOffset,
+    };
+
+    struct Recording(Mutex<Vec<(Vec<u8>, Option<TileOffset>)>>);
+
+    impl BlobRasterizer for Recording {
+      fn rasterize(&self, commands: &[u8], params: &BlobImageParams) -> BlobImageResult {
+        self.0.lock().unwrap().push((commands.to_vec(), params.request.tile));
+        Ok(RasterizedBlobImage {
+          rasterized_rect: DeviceIntRect::new(DeviceIntPoint::zero(), params.descriptor.size),
+          data: Arc::new(vec![0; 4]),
+        })
+      }
+    }
+
+    let mut loader = ImageLoader::default();
+    let updates = record_uploads(&mut loader, WindowId(0));
+    let calls = Arc::new(Recording(Mutex::new(Vec::new())));
+    let descriptor = ImageDescriptor::new(512, 256, ImageFormat::BGRA8, false, false);
+    let info = loader.add_blob_image("chart", descriptor, vec![7, 8], 256, calls.clone());
+
+    // What WebRender does with the resource update, then with the tiles it needs
+    let mut handler = loader.blob_handler();
+    let tiling = match updates.lock().unwrap()[0] {
+      ResourceUpdate::AddImage(ref add) => add.tiling,
+      _ => panic!("the blob is added first"),
+    };
+    handler.add(info.key, Arc::new(vec![7, 8]), tiling);
+    let params = |key, x| BlobImageParams {
+      request: BlobImageRequest { key, tile: Some(TileOffset::new(x, 0)) },
+      descriptor: BlobImageDescriptor {
+        size: DeviceIntSize::new(256, 256),
+        offset: DevicePoint::default(),
+        format: ImageFormat::BGRA8,
+      },
+      dirty_rect: DirtyRect::All,
+    };
+    let unknown = ImageKey::new(IdNamespace(9), 9);
+    let requests = [params(info.key, 0), params(info.key, 1), params(unknown, 0)];
+    let results = handler.create_blob_rasterizer().rasterize(&requests, false);
+
+    assert_eq!(tiling, Some(256));
+    let seen = calls.0.lock().unwrap().clone();
+    let tiles: Vec<_> = [0, 1].iter().map(|&x| (vec![7, 8], Some(TileOffset::new(x, 0)))).collect();
+    assert_eq!(seen, tiles);
+    assert!(results[0].1.is_ok() && results[1].1.is_ok());
+    match results[2].1 {
+      Err(BlobImageError::InvalidKey) => {}
+      ref other => panic!("unexpected result {:?}", other),
+    }
+
+    // Only blobs are updated as blobs, and unloading drops the rasterizer
+    loader.load_image("icon", png(1, 1, ColorType::RGBA(8), &[0, 0, 0, 255])).unwrap();
+    assert!(!loader.update_blob_image("icon", vec![1], DirtyRect::All));
+    assert!(loader.update_blob_image("chart", vec![1], DirtyRect::All));
+    loader.unload_image(&ImageSource::bundled("chart"));
+    assert!(loader.blob_rasterizers.lock().unwrap().is_empty());
+    assert!(!loader.update_blob_image("chart", vec![1], DirtyRect::All));
+  }
+
+  #[test]
+  fn alpha_weighted_resize_keeps_transparent_colors_out() {
+    // An opaque red pixel next to transparent green ones, which straight filtering would mix in
+    let edge = RgbaImage::from_fn(2, 2, |x, y| match (x, y) {
+      (0, 0) => image::Rgba { data: [255, 0, 0, 255] },
+      _ => image::Rgba { data: [0, 255, 0, 0] },
+    });
+    let resized = resize_alpha_weighted(&DynamicImage::ImageRgba8(edge), 1, 1).to_rgba();
+
+    let [r, g, b, a] = resized.get_pixel(0, 0).data;
+    assert!(a > 0 && a < 255, "coverage is averaged, got {}", a);
+    assert!(r >= 250 && g == 0 && b == 0, "fringed color {:?}", [r, g, b]);
+  }
+
+  #[test]
+  fn variants_of_one_source_keep_their_own_keys() {
+    let mut loader = loader_serving(png(2, 1, ColorType::RGBA(8), &[200, 100, 50, 255, 10, 20, 30, 255]));
+    let updates = record_uploads(&mut loader, WindowId::default());
+    let swatch = ImageSource::asset("swatch.png");
+    let tint = Pipeline::new().then(::pipeline::Tint([255, 0, 0, 255]));
+
+    let original = loader.get_image(&swatch).unwrap().key;
+    let tinted = loader.get_image_with_pipeline(&swatch, &tint).unwrap().key;
+    let scaled = loader.get_image_integer_scaled(&swatch, 2).unwrap().key;
+
+    let added = added_images(&updates);
+    assert_eq!(added.len(), 3);
+    assert_eq!((added[1].0, added[1].1.size), (tinted, DeviceIntSize::new(2, 1)));
+    assert_eq!(added[1].2, vec![0, 0, 200, 255, 0, 0, 10, 255]);
+    assert_eq!((added[2].0, added[2].1.size), (scaled, DeviceIntSize::new(4, 2)));
+    assert_eq!(&added[2].2[..8], &[50, 100, 200, 255, 50, 100, 200, 255]);
+
+    // Asking again resolves each variant to its own upload
+    assert_eq!(loader.get_image_with_pipeline(&swatch, &tint).unwrap().key, tinted);
+    assert_eq!(loader.get_image_integer_scaled(&swatch, 2).unwrap().key, scaled);
+    assert_eq!(loader.get_image(&swatch).unwrap().key, original);
+    assert_eq!(added_images(&updates).len(), 3);
+    let variants = [Variant::Original, Variant::Pipeline(tint.identity()), Variant::IntegerScaled(2)];
+    for (&variant, &key) in variants.iter().zip([original, tinted, scaled].iter()) {
+      assert_eq!(loader.uploads.images[&CacheKey::new(swatch.clone(), variant)].key, key);
+    }
+  }
+
+  #[test]
+  fn checkerboards_alternate_and_are_generated_once() {
+    let mut loader = ImageLoader::new();
+    let updates = record_uploads(&mut loader, WindowId::default());
+    let key = loader.transparency_checkerboard(2).unwrap().key;
+    assert_eq!(loader.transparency_checkerboard(2).unwrap().key, key);
+
+    let added = added_images(&updates);
+    assert_eq!(added.len(), 1);
+    assert_eq!(added[0].1.size, DeviceIntSize::new(4, 4));
+    for y in 0..4 {
+      for x in 0..4 {
+        let expected = if (x / 2 + y / 2) % 2 == 0 { 0xff } else { 0xcc };
+        let offset = (y * 4 + x) * 4;
+        assert_eq!(&added[0].2[offset..offset + 4], &[expected, expected, expected, 0xff], "at {}x{}", x, y);
+      }
+    }
+
+    assert!(loader.transparency_checkerboard(0).is_err());
+    assert!(loader.transparency_checkerboard(0x8000_0000).is_err());
+    assert_eq!(added_images(&updates).len(), 1);
+  }
+
+  #[test]
+  fn exported_manifests_warm_a_new_loader() {
+    let icon = png(2, 2, ColorType::RGBA(8), &[90; 16]);
+    let mut loader = loader_serving(icon.clone());
+    record_uploads(&mut loader, WindowId::default());
+    let (kept, gone) = (ImageSource::asset("kept.png"), ImageSource::asset("gone.png"));
+    loader.get_image(&kept).unwrap();
+    loader.get_image_mip(&kept, 1).unwrap();
+    loader.get_image_integer_scaled(&kept, 3).unwrap();
+    loader.get_image(&gone).unwrap();
+
+    let path = temp_dir("manifest").join("warm.manifest");
+    loader.export_manifest(&path).unwrap();
+    let mut text = fs::read_to_string(&path).unwrap();
+    text.push_str("mip\tnot a level\tasset\tkept.png\n");
+    fs::write(&path, text).unwrap();
+
+    let mut warm = ImageLoader::new();
+    warm.set_source_resolver(move |source| match *source {
+      ImageSource::AssetPath(ref path) if path.ends_with("gone.png") => bail!("gone"),
+      _ => Ok(Some(icon.clone())),
+    });
+    let updates = record_uploads(&mut warm, WindowId::default());
+    let report = warm.warm_from_manifest(&path).unwrap();
+
+    assert_eq!(report.warmed, 3);
+    assert_eq!(report.malformed, vec!["mip\tnot a level\tasset\tkept.png".to_string()]);
+    assert_eq!(report.failed.len(), 1);
+    assert_eq!(report.failed[0].0, ManifestEntry::Original(gone));
+    assert_eq!(added_images(&updates).len(), 3);
+    for &variant in &[Variant::Original, Variant::Mip(1), Variant::IntegerScaled(3)] {
+      assert!(warm.uploads.images.contains_key(&CacheKey::new(kept.clone(), variant)));
+    }
+  }
+
+  #[test]
+  fn integer_scaling_replicates_pixels_into_blocks() {
+    let pixels = [10, 20, 30, 255, 40, 50, 60, 255, 70, 80, 90, 255, 100, 110, 120, 255];
+    let mut loader = loader_serving(png(2, 2, ColorType::RGBA(8), &pixels));
+    let updates = record_uploads(&mut loader, WindowId::default());
+    let info = loader.get_image_integer_scaled(&ImageSource::asset("sprite.png"), 2).unwrap();
+
+    assert_eq!(info.sampling.rendering, ImageRendering::Pixelated);
+    let added = added_images(&updates);
+    assert_eq!(added[0].1.size, DeviceIntSize::new(4, 4));
+    for y in 0..4 {
+      for x in 0..4 {
+        let source = &pixels[(y / 2 * 2 + x / 2) * 4..][..4];
+        let offset = (y * 4 + x) * 4;
+        let bgra = [source[2], source[1], source[0], source[3]];
+        assert_eq!(&added[0].2[offset..offset + 4], &bgra, "at {}x{}", x, y);
+      }
+    }
+  }
+
+  #[test]
+  fn luminance_spans_black_to_white() {
+    let (black, white) = (png(2, 2, ColorType::RGB(8), &[0; 12]), png(2, 2, ColorType::RGB(8), &[255; 12]));
+    let mut loader = ImageLoader::new();
+    loader.set_source_resolver(move |source| match *source {
+      ImageSource::AssetPath(ref path) if path.ends_with("black.png") => Ok(Some(black.clone())),
+      _ => Ok(Some(white.clone())),
+    });
+
+    let dark = loader.average_luminance(&ImageSource::asset("black.png")).unwrap();
+    let bright = loader.average_luminance(&ImageSource::asset("white.png")).unwrap();
+    assert!(dark.abs() < 0.01, "black averages {}", dark);
+    assert!((bright - 1.0).abs() < 0.01, "white averages {}", bright);
+    assert!(loader.average_luminances.contains_key(&ImageSource::asset("white.png")));
+  }
+
+  #[test]
+  fn resolved_sources_skip_the_disk_and_others_fall_back_to_it() {
+    let dir = temp_dir("resolver_fallback");
+    fs::write(dir.join("disk.png"), png(1, 1, ColorType::RGBA(8), &[200, 0, 0, 255])).unwrap();
+    let served = png(1, 1, ColorType::RGBA(8), &[0, 200, 0, 255]);
+    let mut loader = ImageLoader::new();
+    loader.assets_path = dir.clone();
+    loader.set_source_resolver(move |source| match *source {
+      ImageSource::AssetPath(ref path) if path.ends_with("served.png") => Ok(Some(served.clone())),
+      _ => Ok(None),
+    });
+    let updates = record_uploads(&mut loader, WindowId::default());
+
+    loader.get_image(&ImageSource::asset("served.png")).unwrap();
+    loader.get_image(&ImageSource::asset("disk.png")).unwrap();
+    let added = added_images(&updates);
+    assert_eq!(added.len(), 2);
+    assert_eq!(added[0].2, vec![0, 200, 0, 255]);
+    assert_eq!(added[1].2, vec![0, 0, 200, 255]);
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn each_window_uploads_through_its_own_api() {
+    let mut loader = loader_serving(png(1, 1, ColorType::RGBA(8), &[1, 2, 3, 255]));
+    let (window_a, window_b) = (WindowId(0), WindowId(1));
+    let updates_a = record_uploads(&mut loader, window_a);
+    let updates_b = record_uploads(&mut loader, window_b);
+    let icon = ImageSource::asset("icon.png");
+
+    let key_a = loader.get_image(&icon).unwrap().key;
+    assert_eq!(key_a.0, IdNamespace(1));
+    assert_eq!((added_images(&updates_a).len(), added_images(&updates_b).len()), (1, 0));
+
+    loader.set_active_window(window_b);
+    let key_b = loader.get_image(&icon).unwrap().key;
+    assert_eq!(key_b.0, IdNamespace(2));
+    assert_eq!((added_images(&updates_a).len(), added_images(&updates_b).len()), (1, 1));
+    loader.unload_image(&icon);
+    assert_eq!(updates_b.lock().unwrap().len(), 2);
+    assert_eq!((loader.stats().cache_misses, loader.stats().evictions), (1, 1));
+
+    // Window A kept its upload and its own counters
+    loader.set_active_window(window_a);
+    assert_eq!(updates_a.lock().unwrap().len(), 1);
+    assert_eq!(loader.get_image(&icon).unwrap().key, key_a);
+    let stats = loader.stats();
+    assert_eq!((stats.cache_hits, stats.cache_misses, stats.evictions), (1, 1, 0));
+  }
+
+  #[test]
+  fn rounded_masks_are_opaque_inside_and_antialiased_at_the_corners() {
+    let mut loader = ImageLoader::new();
+    let updates = record_uploads(&mut loader, WindowId::default());
+    let info = loader.rounded_rect_mask(16, 8, 4).unwrap();
+    assert_eq!(loader.rounded_rect_mask(16, 8, 4).unwrap().key, info.key);
+
+    let added = added_images(&updates);
+    assert_eq!(added.len(), 1);
+    assert_eq!((added[0].1.format, added[0].1.size), (ImageFormat::R8, DeviceIntSize::new(16, 8)));
+    let coverage = |x: usize, y: usize| added[0].2[y * 16 + x];
+    assert_eq!((coverage(8, 4), coverage(1, 4), coverage(8, 0)), (255, 255, 255));
+    for &(x, y) in &[(0, 0), (15, 0), (0, 7), (15, 7)] {
+      assert_eq!(coverage(x, y), 0, "outside the arc at {}x{}", x, y);
+    }
+    // The arc crosses these pixels, leaving them partially covered
+    for &(x, y) in &[(1, 1), (14, 1), (1, 6), (14, 6)] {
+      assert!(coverage(x, y) > 0 && coverage(x, y) < 255, "{} at {}x{}", coverage(x, y), x, y);
+    }
+
+    assert!(loader.rounded_rect_mask(0x10000, 0x10000, 4).is_err());
+  }
+
+  #[test]
+  fn banded_progress_counts_rows_up_to_the_height() {
+    let mut loader = loader_serving(png(3, 40, ColorType::Gray(8), &[128; 3 * 40]));
+    record_uploads(&mut loader, WindowId::default());
+    let mut calls = vec![];
+    let banded = loader.get_image_banded_with_progress(&ImageSource::asset("scan.png"), 8, |done, total| {
+      calls.push((done, total));
+    });
+    assert_eq!(banded.unwrap().bands.len(), 5);
+
+    assert!(calls.iter().all(|&(_, total)| total == 40));
+    assert!(calls.windows(2).all(|pair| pair[0].0 < pair[1].0), "{:?}", calls);
+    assert_eq!(calls.last(), Some(&(40, 40)));
+  }
+
+  #[test]
+  fn quantized_exports_hold_at_most_the_palette() {
+    let gradient: Vec<u8> = (0..256).flat_map(|i| vec![i as u8, (i * 7) as u8, 255 - i as u8, 255]).collect();
+    let loader = loader_serving(png(16, 16, ColorType::RGBA(8), &gradient));
+    let source = ImageSource::asset("gradient.png");
+    let dir = temp_dir("quantized");
+
+    let path = dir.join("small.PNG");
+    loader.save_image_quantized(&source, &path, 4).unwrap();
+    let saved = image::open(&path).unwrap().to_rgba();
+    let mut colors: Vec<_> = saved.pixels().map(|pixel| pixel.data).collect();
+    colors.sort();
+    colors.dedup();
+    assert!(colors.len() <= 4, "holds {} colors", colors.len());
+    // An indexed PNG, not RGBA with few colors
+    assert_eq!(fs::read(&path).unwrap()[25], 3);
+
+    let error = loader.save_image_quantized(&source, dir.join("small.jpg"), 4).unwrap_err();
+    assert!(error.to_string().contains(".png or .gif"));
+    assert!(!dir.join("small.jpg").exists());
+    for &max_colors in [1, 257].iter() {
+      assert!(loader.save_image_quantized(&source, &path, max_colors).is_err());
+    }
+  }
+
+  #[test]
+  fn pipelines_run_their_stages_in_order_and_cache_by_identity() {
+    use pipeline::{Grayscale, Tint};
+
+    let mut loader = loader_serving(png(1, 1, ColorType::RGBA(8), &[200, 100, 50, 255]));
+    let updates = record_uploads(&mut loader, WindowId::default());
+    let photo = ImageSource::asset("photo.png");
+    let toned = || Pipeline::new().then(Grayscale).then(Tint([255, 128, 0, 255]));
+
+    // Luma 118, then tinted: the BGRA upload of (118, 59, 0)
+    let key = loader.get_image_with_pipeline(&photo, &toned()).unwrap().key;
+    assert_eq!(added_images(&updates)[0].2, vec![0, 59, 118, 255]);
+    assert_eq!(loader.get_image_with_pipeline(&photo, &toned()).unwrap().key, key);
+    assert_eq!(added_images(&updates).len(), 1);
+
+    // Other stage orders and parameters are other uploads
+    let reversed = Pipeline::new().then(Tint([255, 128, 0, 255])).then(Grayscale);
+    assert_ne!(loader.get_image_with_pipeline(&photo, &reversed).unwrap().key, key);
+    assert_eq!(added_images(&updates)[1].2, vec![78, 78, 78, 255]);
+    let bluer = Pipeline::new().then(Grayscale).then(Tint([255, 128, 64, 255]));
+    assert_ne!(loader.get_image_with_pipeline(&photo, &bluer).unwrap().key, key);
+    assert_eq!(added_images(&updates).len(), 3);
   }
 }
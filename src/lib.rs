@@ -4,16 +4,30 @@ extern crate lazy_static;
 extern crate failure_derive;
 #[macro_use]
 extern crate failure;
+extern crate color_quant;
+extern crate gif;
 extern crate image;
+extern crate png;
+#[cfg(feature = "bytes")]
+extern crate bytes;
 extern crate webrender;
 
+pub mod animation;
+pub mod atlas;
+pub mod blob;
+pub mod color_lut;
+pub mod color_space;
 pub mod images;
+pub mod manifest;
+pub mod pipeline;
+pub mod pixel_layout;
+pub mod quantize;
 
 use std::sync::{Mutex, MutexGuard};
 use std::default::Default;
 
 use webrender::api::RenderApiSender;
-use self::images::ImageLoader;
+use self::images::{ImageLoader, WindowId};
 
 lazy_static! {
   static ref RESOURCES: Mutex<Resources> = Mutex::new(Resources::new());
@@ -23,11 +37,21 @@ pub fn init_resources(render_api: RenderApiSender) {
   RESOURCES.try_lock().unwrap().set_render_api(render_api);
 }
 
-// Allow global access to Resources
+/// Registers the render API of an additional window, see `ImageLoader::set_active_window`.
+pub fn init_window_resources(window: WindowId, render_api: RenderApiSender) {
+  RESOURCES.try_lock().unwrap().image_loader.register_render_api(window, render_api.create_api());
+}
+
+// Allow global access to Resources. The lock is taken with try_lock, so using it from two
+// threads at the same time panics instead of blocking.
 pub fn resources() -> MutexGuard<'static, Resources> {
   RESOURCES.try_lock().unwrap()
 }
 
+/// Shared behind the global `Mutex`, which is only `Sync` because `Resources` is `Send`. The
+/// `RenderApi` in the image loader can be moved across threads but not shared between them,
+/// so the loader is deliberately `Send` and not `Sync`: reach it through `resources()` or own
+/// it on a single thread. Both bounds are compile checked by `tests/thread_safety.rs`.
 pub struct Resources {
   pub image_loader: ImageLoader,
 }
@@ -46,6 +70,6 @@ impl Resources {
   }
 
   fn set_render_api(&mut self, render: RenderApiSender) {
-    self.image_loader.render = Some(render.create_api());
+    self.image_loader.register_render_api(WindowId::default(), render.create_api());
   }
 }
@@ -0,0 +1,110 @@
+use std::path::PathBuf;
+
+use images::ImageSource;
+
+/// One cached image worth reloading on the next launch. Pixels are never recorded, only what
+/// it takes to produce them again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ManifestEntry {
+  Original(ImageSource),
+  Mip(ImageSource, u32),
+  IntegerScaled(ImageSource, u32),
+  /// An image graded through the Hald CLUT loaded from the second source.
+  Graded(ImageSource, ImageSource),
+}
+
+// Each entry is a line of tab separated fields, the variant followed by its sources, e.g.
+// `mip\t2\tasset\ticons/close.png`. Sources only backed by memory cannot be replayed and
+// paths that are not valid UTF-8 or contain tabs or newlines cannot be written.
+
+/// Formats the entry as a manifest line, `None` when one of its sources can't be recorded.
+pub fn format_entry(entry: &ManifestEntry) -> Option<String> {
+  let mut fields = vec![];
+  match *entry {
+    ManifestEntry::Original(ref source) => {
+      fields.push("original".to_string());
+      push_source(&mut fields, source)?;
+    }
+    ManifestEntry::Mip(ref source, level) => {
+      fields.push("mip".to_string());
+      fields.push(level.to_string());
+      push_source(&mut fields, source)?;
+    }
+    ManifestEntry::IntegerScaled(ref source, factor) => {
+      fields.push("integer".to_string());
+      fields.push(factor.to_string());
+      push_source(&mut fields, source)?;
+    }
+    ManifestEntry::Graded(ref source, ref lut_source) => {
+      fields.push("graded".to_string());
+      push_source(&mut fields, source)?;
+      push_source(&mut fields, lut_source)?;
+    }
+  }
+  Some(fields.join("\t"))
+}
+
+/// Reads back a line written by `format_entry`, `None` when it is malformed.
+pub fn parse_entry(line: &str) -> Option<ManifestEntry> {
+  let mut fields = line.split('\t');
+  let entry = match fields.next()? {
+    "original" => ManifestEntry::Original(parse_source(&mut fields)?),
+    "mip" => {
+      let level = fields.next()?.parse().ok()?;
+      ManifestEntry::Mip(parse_source(&mut fields)?, level)
+    }
+    "integer" => {
+      let factor = fields.next()?.parse().ok()?;
+      ManifestEntry::IntegerScaled(parse_source(&mut fields)?, factor)
+    }
+    "graded" => {
+      let source = parse_source(&mut fields)?;
+      ManifestEntry::Graded(source, parse_source(&mut fields)?)
+    }
+    _ => return None,
+  };
+
+  match fields.next() {
+    Some(_) => None,
+    None => Some(entry),
+  }
+}
+
+fn push_source(fields: &mut Vec<String>, source: &ImageSource) -> Option<()> {
+  match *source {
+    ImageSource::AbsolutePath(_) => fields.push("absolute".to_string()),
+    ImageSource::AssetPath(_) => fields.push("asset".to_string()),
+    ImageSource::AssetWithRoot(ref root, _) => {
+      if !is_field(root) {
+        return None;
+      }
+      fields.push("root".to_string());
+      fields.push(root.clone());
+    }
+    _ => return None,
+  }
+
+  let path = source.as_path()?.to_str()?;
+  if !is_field(path) {
+    return None;
+  }
+  fields.push(path.to_string());
+  Some(())
+}
+
+fn parse_source<'a, I: Iterator<Item = &'a str>>(fields: &mut I) -> Option<ImageSource> {
+  let source = match fields.next()? {
+    "absolute" => ImageSource::AbsolutePath(PathBuf::from(fields.next()?)),
+    "asset" => ImageSource::AssetPath(PathBuf::from(fields.next()?)),
+    "root" => {
+      let root = fields.next()?;
+      ImageSource::asset_with_root(root, fields.next()?)
+    }
+    _ => return None,
+  };
+  Some(source)
+}
+
+fn is_field(text: &str) -> bool {
+  !text.is_empty() && !text.contains(|c| c == '\t' || c == '\n' || c == '\r')
+}
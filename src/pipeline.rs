@@ -0,0 +1,142 @@
+use std::any::TypeId;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::mem;
+
+use image::{DynamicImage, RgbaImage};
+
+use color_lut::ColorLut;
+use images;
+
+/// A transformation of decoded pixels, run before premultiplication and upload.
+pub trait PixelStage {
+  fn process(&self, image: &mut RgbaImage);
+
+  /// Hash of the stage parameters. Two stages of the same type returning the same identity
+  /// must produce the same pixels, since uploads are cached by it.
+  fn identity(&self) -> u64;
+}
+
+/// Ordered stages applied to an image, with an identity combining the type and identity of
+/// every stage in order.
+#[derive(Default)]
+pub struct Pipeline {
+  stages: Vec<Box<dyn PixelStage>>,
+  identity: u64,
+}
+
+impl Pipeline {
+  pub fn new() -> Self {
+    Pipeline::default()
+  }
+
+  /// Appends a stage running after the ones already added.
+  pub fn then<S: PixelStage + 'static>(mut self, stage: S) -> Self {
+    let mut hasher = DefaultHasher::new();
+    self.identity.hash(&mut hasher);
+    TypeId::of::<S>().hash(&mut hasher);
+    stage.identity().hash(&mut hasher);
+    self.identity = hasher.finish();
+    self.stages.push(Box::new(stage));
+    self
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.stages.is_empty()
+  }
+
+  /// Only meaningful within one run of the program, type ids are not stable across builds.
+  pub fn identity(&self) -> u64 {
+    self.identity
+  }
+
+  pub fn process(&self, image: &mut RgbaImage) {
+    for stage in &self.stages {
+      stage.process(image);
+    }
+  }
+}
+
+/// Replaces colors with their Rec. 709 luma, keeping alpha.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Grayscale;
+
+impl PixelStage for Grayscale {
+  fn process(&self, image: &mut RgbaImage) {
+    for pixel in image.pixels_mut() {
+      let [r, g, b, _] = pixel.data;
+      let luma = 0.2126 * f32::from(r) + 0.7152 * f32::from(g) + 0.0722 * f32::from(b);
+      let luma = luma.round().min(255.0) as u8;
+      pixel.data[0] = luma;
+      pixel.data[1] = luma;
+      pixel.data[2] = luma;
+    }
+  }
+
+  fn identity(&self) -> u64 {
+    0
+  }
+}
+
+/// Multiplies every channel, alpha included, by an RGBA color.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Tint(pub [u8; 4]);
+
+impl PixelStage for Tint {
+  fn process(&self, image: &mut RgbaImage) {
+    for pixel in image.pixels_mut() {
+      for (channel, &tint) in pixel.data.iter_mut().zip(self.0.iter()) {
+        *channel = ((u32::from(*channel) * u32::from(tint) + 128) / 255) as u8;
+      }
+    }
+  }
+
+  fn identity(&self) -> u64 {
+    hash_of(self)
+  }
+}
+
+/// Remaps colors through a lookup table, as `ImageLoader::get_image_graded` does.
+#[derive(Debug, Clone)]
+pub struct Lut(pub ColorLut);
+
+impl PixelStage for Lut {
+  fn process(&self, image: &mut RgbaImage) {
+    self.0.apply(image);
+  }
+
+  fn identity(&self) -> u64 {
+    hash_of(&self.0)
+  }
+}
+
+/// Replicates every pixel into a block, as `ImageLoader::get_image_integer_scaled` does. As a
+/// stage it can't set the pixelated sampling hint, display items should ask for it. Factors
+/// overflowing the image size leave it unchanged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IntegerScale(pub u32);
+
+impl PixelStage for IntegerScale {
+  fn process(&self, image: &mut RgbaImage) {
+    let factor = self.0.max(1);
+    let (width, height) = image.dimensions();
+    if factor == 1 || width.checked_mul(factor).and(height.checked_mul(factor)).is_none() {
+      return;
+    }
+
+    let rgba = DynamicImage::ImageRgba8(mem::replace(image, RgbaImage::new(0, 0)));
+    if let DynamicImage::ImageRgba8(scaled) = images::scale_integer(&rgba, factor) {
+      *image = scaled;
+    }
+  }
+
+  fn identity(&self) -> u64 {
+    hash_of(self)
+  }
+}
+
+fn hash_of<T: Hash>(value: &T) -> u64 {
+  let mut hasher = DefaultHasher::new();
+  value.hash(&mut hasher);
+  hasher.finish()
+}
@@ -0,0 +1,100 @@
+use failure::Error;
+use image::RgbaImage;
+
+use images::unpremultiply;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ChannelOrder {
+  Rgba,
+  Bgra,
+  Argb,
+  Abgr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AlphaKind {
+  Straight,
+  Premultiplied,
+}
+
+/// Bits per channel, 16 bit channels are read as little endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BitDepth {
+  Eight,
+  Sixteen,
+}
+
+/// Describes how the pixels of an external buffer are laid out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PixelLayout {
+  pub order: ChannelOrder,
+  pub alpha: AlphaKind,
+  pub depth: BitDepth,
+}
+
+#[derive(Debug, Fail)]
+#[fail(display = "raw image data has {} bytes, expected {}", actual, expected)]
+struct RawDataSizeError {
+  expected: usize,
+  actual: usize,
+}
+
+impl PixelLayout {
+  pub fn new(order: ChannelOrder, alpha: AlphaKind, depth: BitDepth) -> Self {
+    PixelLayout {
+      order,
+      alpha,
+      depth,
+    }
+  }
+
+  pub fn bytes_per_pixel(&self) -> usize {
+    match self.depth {
+      BitDepth::Eight => 4,
+      BitDepth::Sixteen => 8,
+    }
+  }
+
+  /// Converts a buffer in this layout to straight alpha RGBA8.
+  pub fn to_rgba_image(&self, width: u32, height: u32, data: &[u8]) -> Result<RgbaImage, Error> {
+    let expected = width as usize * height as usize * self.bytes_per_pixel();
+    if data.len() != expected {
+      return Err(
+        RawDataSizeError {
+          expected,
+          actual: data.len(),
+        }.into(),
+      );
+    }
+
+    let offsets = self.channel_offsets();
+    let mut bytes = Vec::with_capacity(width as usize * height as usize * 4);
+    for pixel in data.chunks(self.bytes_per_pixel()) {
+      for &offset in offsets.iter() {
+        bytes.push(match self.depth {
+          BitDepth::Eight => pixel[offset],
+          BitDepth::Sixteen => {
+            let value = u32::from(pixel[offset * 2]) | (u32::from(pixel[offset * 2 + 1]) << 8);
+            ((value + 128) / 257) as u8
+          }
+        });
+      }
+    }
+
+    if self.alpha == AlphaKind::Premultiplied {
+      unpremultiply(bytes.as_mut_slice());
+    }
+
+    Ok(RgbaImage::from_raw(width, height, bytes).unwrap())
+  }
+
+  // Position of the red, green, blue and alpha channels within a pixel
+  fn channel_offsets(&self) -> [usize; 4] {
+    match self.order {
+      ChannelOrder::Rgba => [0, 1, 2, 3],
+      ChannelOrder::Bgra => [2, 1, 0, 3],
+      ChannelOrder::Argb => [1, 2, 3, 0],
+      ChannelOrder::Abgr => [3, 2, 1, 0],
+    }
+  }
+}
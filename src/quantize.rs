@@ -0,0 +1,144 @@
+use std::borrow::Cow;
+use std::io::Write;
+
+use color_quant::NeuQuant;
+use failure::Error;
+use gif;
+use image::RgbaImage;
+use png::{self, HasParameters};
+
+/// An image reduced to a palette of at most 256 colors.
+#[derive(Debug, Clone)]
+pub struct IndexedImage {
+  pub width: u32,
+  pub height: u32,
+  /// RGBA entries, four bytes per color.
+  pub palette: Vec<u8>,
+  /// One palette index per pixel, row by row.
+  pub indices: Vec<u8>,
+}
+
+impl IndexedImage {
+  pub fn colors(&self) -> usize {
+    self.palette.len() / 4
+  }
+
+  /// Expands the indices back to RGBA pixels.
+  pub fn to_rgba_image(&self) -> RgbaImage {
+    let mut pixels = Vec::with_capacity(self.indices.len() * 4);
+    for &index in &self.indices {
+      let offset = index as usize * 4;
+      pixels.extend_from_slice(&self.palette[offset..offset + 4]);
+    }
+    RgbaImage::from_raw(self.width, self.height, pixels).unwrap()
+  }
+}
+
+/// Writes the image as a single frame GIF with the palette as its local color table. GIF has
+/// no partial transparency, the most transparent palette entry becomes fully transparent when
+/// its alpha is below half.
+pub fn write_gif<W: Write>(indexed: &IndexedImage, writer: W) -> Result<(), Error> {
+  if indexed.width > u32::from(u16::max_value()) || indexed.height > u32::from(u16::max_value()) {
+    bail!("{}x{} is too large for a GIF", indexed.width, indexed.height);
+  }
+
+  let rgb: Vec<u8> = indexed.palette.chunks(4).flat_map(|color| color[..3].to_vec()).collect();
+  let transparent = indexed
+    .palette
+    .chunks(4)
+    .enumerate()
+    .min_by_key(|&(_, color)| color[3])
+    .and_then(|(index, color)| if color[3] < 128 { Some(index as u8) } else { None });
+
+  let mut frame = gif::Frame::default();
+  frame.width = indexed.width as u16;
+  frame.height = indexed.height as u16;
+  frame.palette = Some(rgb);
+  frame.transparent = transparent;
+  frame.buffer = Cow::Borrowed(&indexed.indices);
+
+  let mut encoder = gif::Encoder::new(writer, frame.width, frame.height, &[])?;
+  encoder.write_frame(&frame)?;
+  Ok(())
+}
+
+/// Writes the image as an 8 bit indexed PNG, with the palette alpha in a tRNS chunk when any
+/// entry is not opaque.
+pub fn write_png<W: Write>(indexed: &IndexedImage, writer: W) -> Result<(), Error> {
+  let rgb: Vec<u8> = indexed.palette.chunks(4).flat_map(|color| color[..3].to_vec()).collect();
+  let mut alpha: Vec<u8> = indexed.palette.chunks(4).map(|color| color[3]).collect();
+  // Entries past the end of tRNS are opaque
+  while alpha.last() == Some(&255) {
+    alpha.pop();
+  }
+
+  let mut encoder = png::Encoder::new(writer, indexed.width, indexed.height);
+  encoder.set(png::ColorType::Indexed).set(png::BitDepth::Eight);
+  let mut writer = encoder.write_header()?;
+  writer.write_chunk(png::chunk::PLTE, &rgb)?;
+  if !alpha.is_empty() {
+    writer.write_chunk(png::chunk::tRNS, &alpha)?;
+  }
+  writer.write_image_data(&indexed.indices)?;
+  Ok(())
+}
+
+// NeuQuant sampling factor, 1 is the slowest and best, 30 the fastest
+const SAMPLE_FACTOR: i32 = 10;
+
+/// Picks a palette of `max_colors` with NeuQuant and maps every pixel to it, optionally
+/// diffusing the quantization error to the neighbouring pixels (Floyd-Steinberg) to hide
+/// banding in gradients. `max_colors` must be between 2 and 256.
+pub fn quantize(image: &RgbaImage, max_colors: usize, dither: bool) -> IndexedImage {
+  let (width, height) = image.dimensions();
+  let quantizer = NeuQuant::new(SAMPLE_FACTOR, max_colors, image);
+  let palette = quantizer.color_map_rgba();
+
+  let indices = if dither {
+    diffuse(image, &quantizer, &palette)
+  } else {
+    image.chunks(4).map(|pixel| quantizer.index_of(pixel) as u8).collect()
+  };
+
+  IndexedImage {
+    width,
+    height,
+    palette,
+    indices,
+  }
+}
+
+fn diffuse(image: &RgbaImage, quantizer: &NeuQuant, palette: &[u8]) -> Vec<u8> {
+  let (width, height) = (image.width() as usize, image.height() as usize);
+  let mut pixels: Vec<f32> = image.iter().map(|&value| f32::from(value)).collect();
+  let mut indices = Vec::with_capacity(width * height);
+
+  for y in 0..height {
+    for x in 0..width {
+      let offset = (y * width + x) * 4;
+      let mut pixel = [0u8; 4];
+      for (channel, value) in pixel.iter_mut().enumerate() {
+        *value = pixels[offset + channel].max(0.0).min(255.0).round() as u8;
+      }
+
+      let index = quantizer.index_of(&pixel);
+      indices.push(index as u8);
+
+      for channel in 0..4 {
+        let error = f32::from(pixel[channel]) - f32::from(palette[index * 4 + channel]);
+        let mut spread = |dx: isize, dy: usize, weight: f32| {
+          let nx = x as isize + dx;
+          if nx >= 0 && (nx as usize) < width && y + dy < height {
+            pixels[((y + dy) * width + nx as usize) * 4 + channel] += error * weight;
+          }
+        };
+        spread(1, 0, 7.0 / 16.0);
+        spread(-1, 1, 3.0 / 16.0);
+        spread(0, 1, 5.0 / 16.0);
+        spread(1, 1, 1.0 / 16.0);
+      }
+    }
+  }
+
+  indices
+}
@@ -0,0 +1,8 @@
+extern crate trybuild;
+
+#[test]
+fn loader_is_send_but_not_sync() {
+  let cases = trybuild::TestCases::new();
+  cases.pass("tests/ui/loader_send.rs");
+  cases.compile_fail("tests/ui/loader_not_sync.rs");
+}
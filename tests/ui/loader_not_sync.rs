@@ -0,0 +1,11 @@
+extern crate resources;
+
+use resources::images::ResourceApi;
+
+fn is_sync<T: ?Sized + Sync>() {}
+
+// The loader owns its render API through this trait, which doesn't require `Sync` as
+// `RenderApi` isn't, so the loader can't be shared between threads either
+fn main() {
+  is_sync::<dyn ResourceApi>();
+}
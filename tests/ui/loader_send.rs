@@ -0,0 +1,11 @@
+extern crate resources;
+
+use resources::images::ImageLoader;
+use resources::Resources;
+
+fn is_send<T: Send>() {}
+
+fn main() {
+  is_send::<Resources>();
+  is_send::<ImageLoader>();
+}